@@ -1,7 +1,17 @@
-use crate::{lua::LuaContext, paths, rules::RulesManager};
+use crate::{
+    lua::{LuaContext, PdxModule, ScriptLimits, ScriptLogEntry},
+    mods::LoadedMod,
+    paths,
+    pdx::PdxEncoding,
+    rules::{DataRoot, ResolverMode, RuleLayout, RulesManager},
+};
 use anyhow::*;
+use mlua::AnyUserData;
 use serde::*;
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 /// Used to define the game that's being compiled for.
 #[derive(Serialize, Deserialize, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug)]
@@ -21,6 +31,67 @@ impl Game {
     pub fn steam_name(&self) -> &str {
         self.display_name() // technically different, but should mostly be good.
     }
+
+    /// Searches the Steam library folders for this game's data directory.
+    pub fn find_game_data(&self) -> Result<Vec<PathBuf>> {
+        paths::find_game_data(*self)
+    }
+
+    /// Returns the encoding the game expects new files in a given data directory to be saved in.
+    pub fn file_encoding(&self, directory: &str) -> PdxEncoding {
+        match self {
+            Game::Stellaris if directory.split('/').next() == Some("localisation") => {
+                PdxEncoding::Utf8Bom
+            }
+            Game::Stellaris => PdxEncoding::Utf8,
+        }
+    }
+
+    /// Returns how the game resolves multiple definitions of the same rule in a data directory.
+    ///
+    /// This is never [`ResolverMode::Merge`], as the game does not merge definitions itself.
+    pub fn resolver_mode(&self, directory: &str) -> ResolverMode {
+        let mut components = directory.split('/');
+        match self {
+            Game::Stellaris => match (components.next(), components.next()) {
+                (Some("common"), Some("on_actions")) => ResolverMode::Append,
+                (Some("common"), Some("component_templates"))
+                | (Some("common"), Some("event_chains"))
+                | (Some("common"), Some("scripted_variables"))
+                | (Some("common"), Some("special_projects"))
+                | (Some("common"), Some("static_modifiers")) => ResolverMode::Fios,
+                (Some("common"), _) => ResolverMode::Lios,
+                (Some("events"), _) | (Some("gfx"), _) | (Some("interface"), _) => {
+                    ResolverMode::Fios
+                }
+                (Some("localisation"), _) => ResolverMode::Lios,
+                _ => ResolverMode::FileReplace,
+            },
+        }
+    }
+
+    /// Returns how the rules in the files of a data directory are named.
+    pub fn rule_layout(&self, directory: &str) -> RuleLayout {
+        match self {
+            Game::Stellaris => match directory.split('/').next() {
+                Some("events") => RuleLayout::Id,
+                Some("gfx") | Some("interface") => RuleLayout::Container,
+                _ => RuleLayout::Tag,
+            },
+        }
+    }
+}
+
+impl FromStr for Game {
+    type Err = Error;
+
+    /// Parses the name of a game, as used in configuration files, such as `stellaris`.
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "stellaris" => Ok(Game::Stellaris),
+            _ => bail!("Unknown game: {}", name),
+        }
+    }
 }
 
 /// A compiler for Patchling mod definitions.
@@ -37,6 +108,32 @@ impl Compiler {
     pub fn builder(game: Game) -> CompilerBuilder {
         CompilerBuilder::new(game)
     }
+
+    /// Runs a script from one of a mod's source directories, within the configured resource
+    /// limits. Errors raised by the script are returned as a [`ScriptError`].
+    ///
+    /// [`ScriptError`]: crate::ScriptError
+    pub fn execute_script(&self, loaded_mod: &LoadedMod, path: impl AsRef<Path>) -> Result<()> {
+        self.lua_ctx.execute_script(loaded_mod, path.as_ref())
+    }
+
+    /// Compiles metalua source code into minified Lua source code.
+    pub fn compile_and_minify(&self, source: &str, name: &str) -> Result<String> {
+        self.lua_ctx.compile_and_minify(source, name)
+    }
+
+    /// Returns the warnings and errors scripts have logged, in the order they were logged.
+    pub fn script_warnings(&self) -> Vec<ScriptLogEntry> {
+        self.lua_ctx.script_warnings()
+    }
+
+    /// Writes the rules changed by scripts to an output mod directory.
+    pub fn write_output(&self, out_dir: impl AsRef<Path>) -> Result<()> {
+        let lua = self.lua_ctx.lua();
+        let rules: AnyUserData<'_> = lua.globals().get("rules")?;
+        let rules = rules.borrow::<RulesManager>()?;
+        rules.write_output(lua, out_dir.as_ref())
+    }
 }
 
 /// A builder for compiler objects.
@@ -44,11 +141,20 @@ impl Compiler {
 pub struct CompilerBuilder {
     game: Game,
     game_data: Option<PathBuf>,
+    mods: Vec<LoadedMod>,
+    history_comments: bool,
+    limits: ScriptLimits,
 }
 impl CompilerBuilder {
     /// Creates a new compiler builder.
     pub fn new(game: Game) -> Self {
-        CompilerBuilder { game, game_data: None }
+        CompilerBuilder {
+            game,
+            game_data: None,
+            mods: Vec::new(),
+            history_comments: false,
+            limits: ScriptLimits::default(),
+        }
     }
 
     pub fn game_data(mut self, path: impl Into<PathBuf>) -> Self {
@@ -56,6 +162,33 @@ impl CompilerBuilder {
         self
     }
 
+    /// Adds a mod to the playset. Mods are loaded in the order they are added, and scripts from a
+    /// mod may require modules from its library directories.
+    pub fn add_mod(mut self, loaded_mod: LoadedMod) -> Self {
+        self.mods.push(loaded_mod);
+        self
+    }
+
+    /// Sets whether each rule in the output mod is preceded by comments explaining where it was
+    /// defined and how scripts changed it.
+    pub fn history_comments(mut self, history_comments: bool) -> Self {
+        self.history_comments = history_comments;
+        self
+    }
+
+    /// Sets the maximum number of Lua instructions a single script may run.
+    pub fn instruction_limit(mut self, limit: u64) -> Self {
+        self.limits.instructions = Some(limit);
+        self
+    }
+
+    /// Sets the maximum number of bytes of memory a single script may keep in use. See
+    /// [`ScriptLimits::memory`] for how precisely this is enforced.
+    pub fn memory_limit(mut self, limit: usize) -> Self {
+        self.limits.memory = Some(limit);
+        self
+    }
+
     pub fn build(self) -> Result<Compiler> {
         let root_path = paths::get_lua_root_dir()?;
 
@@ -80,18 +213,17 @@ impl CompilerBuilder {
 
         // Create the Lua context.
         debug!("Initializing Lua context...");
-        let lua_ctx = LuaContext::new(root_path, &[])?;
-        let rules = RulesManager::new(self.game);
+        let lua_ctx = LuaContext::new(root_path, &self.mods, self.limits)?;
+        let mut rules = RulesManager::new(self.game);
+        rules.add_data_root(DataRoot::vanilla(game_data));
+        for loaded_mod in &self.mods {
+            for dir in &loaded_mod.info.copy_dirs {
+                rules.add_data_root(DataRoot::mod_data(loaded_mod.info.id.clone(), dir.clone()));
+            }
+        }
+        rules.set_history_comments(self.history_comments);
         lua_ctx.register_module("rules", rules)?;
-
-        // TODO: Testing
-        println!(
-            "{}",
-            lua_ctx.compile_and_minify(
-                include_str!("../../patchling_rt/patchling_private/mlua/ast_to_src.mlua"),
-                "ast_to_src.mlua",
-            )?
-        );
+        lua_ctx.register_module("pdx", PdxModule)?;
 
         debug!("Compiler initialized!");
         Ok(Compiler { lua_ctx })
@@ -3,9 +3,10 @@ extern crate tracing;
 
 mod common;
 mod lua;
-mod mods;
+pub mod mods;
 mod paths;
-mod pdx;
-mod rules;
+pub mod pdx;
+pub mod rules;
 
 pub use common::*;
+pub use lua::{ScriptError, ScriptLimits, ScriptLogEntry, ScriptLogLevel};
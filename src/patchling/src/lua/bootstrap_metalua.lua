@@ -5,3 +5,7 @@ require_alias("metalua.compiler.globals", "patchling_private.mlua.metalua_global
 
 -- Removes nonpublic (but safe) functions
 require_alias = nil
+
+-- Hides privileged modules from user code
+remove_privileged_modules()
+remove_privileged_modules = nil
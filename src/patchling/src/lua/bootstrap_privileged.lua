@@ -1,7 +1,7 @@
 -- NOTE: This is privileged code and has access to functions that should not be available to user code.
 --       Take extra care when editing this file.
 
-local modules_path, mod_paths = ...
+local modules_path, mod_paths, log_sink = ...
 
 -- Remove unsafe functions.
 function dofile(...)
@@ -36,6 +36,19 @@ do
             package.loaded[target] = require(what)
         end
     end
+
+    -- Removes privileged modules from `package.loaded` once bootstrapping is finished, so user code
+    -- cannot `require` them. Modules that need them keep their own references.
+    function remove_privileged_modules()
+        for name in pairs(package.loaded) do
+            if string.sub(name, 1, 28) == "patchling_private.privileged" then
+                package.loaded[name] = nil
+            end
+        end
+        package.loaded["metalua.loader"] = nil
+        package.loaded["patchling_private.proxy"] = nil
+        package.loaded["patchling_private.script_loader"] = nil
+    end
 end
 
 -- Disable bytecode loading
@@ -85,6 +98,20 @@ package.mod_paths = mod_paths
 package.loaded["checks"] = require "patchling_private.privileged.checks"
 require "patchling_private.privileged.traceback"
 package.loaded["metalua.loader"] = require "patchling_private.privileged.metalua_loader"
+local script_loader = require "patchling_private.privileged.script_loader"
+log = (require "patchling_private.privileged.log")(log_sink)
+package.loaded.log = log
+
+-- Replace the functions that inspect and edit tables with versions that understand rule proxies.
+local proxy
+do
+    proxy = require "patchling_private.privileged.proxy"
+    next, pairs, ipairs, unpack = proxy.next, proxy.pairs, proxy.ipairs, proxy.unpack
+    table.insert, table.remove, table.sort, table.concat = proxy.insert, proxy.remove, proxy.sort, proxy.concat
+    table.isempty, table.nkeys, table.clone = proxy.isempty, proxy.nkeys, proxy.clone
+    table.len = proxy.len
+    type = proxy.type
+end
 
 -- Remove unsafe functions that are used by privileged modules.
 debug = nil
@@ -104,4 +131,7 @@ package.loaded.os = os
 
 -- Seal the package table
 package.loaded.package = nil
-package = nil
\ No newline at end of file
+package = nil
+
+-- Return the privileged modules the compiler calls into directly.
+return script_loader, proxy
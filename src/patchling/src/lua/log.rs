@@ -0,0 +1,83 @@
+use mlua::{prelude::LuaResult, Function, Lua, LuaSerdeExt, Value};
+use serde::*;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// The level of a message logged by a script.
+#[derive(Serialize, Deserialize, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptLogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// A message logged by a script through the `log` module.
+#[derive(Serialize, Clone, Debug)]
+pub struct ScriptLogEntry {
+    pub level: ScriptLogLevel,
+    /// The ID of the mod the message was logged from, or `None` if it was not logged by a mod.
+    pub mod_name: Option<String>,
+    /// The module the message was logged from, relative to the mod's source or library directory.
+    pub module: Option<String>,
+    pub line: Option<u32>,
+    pub message: String,
+}
+impl fmt::Display for ScriptLogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let (Some(mod_name), Some(module)) = (&self.mod_name, &self.module) {
+            write!(f, "{}:{}", mod_name, module)?;
+            if let Some(line) = self.line {
+                write!(f, ":{}", line)?;
+            }
+            f.write_str(": ")?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// Forwards a message logged by a script to `tracing`. Every message uses the `script` target,
+/// with the mod and location it was logged from as fields.
+fn emit(entry: &ScriptLogEntry) {
+    let mod_name = entry.mod_name.as_deref().unwrap_or("patchling");
+    let module = entry.module.as_deref().unwrap_or("<unknown>");
+    let line = entry.line.unwrap_or(0);
+    let message = &entry.message;
+    match entry.level {
+        ScriptLogLevel::Trace => {
+            trace!(target: "script", mod_name, module, line, "{}", message)
+        }
+        ScriptLogLevel::Debug => {
+            debug!(target: "script", mod_name, module, line, "{}", message)
+        }
+        ScriptLogLevel::Info => info!(target: "script", mod_name, module, line, "{}", message),
+        ScriptLogLevel::Warn => warn!(target: "script", mod_name, module, line, "{}", message),
+        ScriptLogLevel::Error => {
+            error!(target: "script", mod_name, module, line, "{}", message)
+        }
+    }
+}
+
+/// Creates the function the `log` module passes messages to. Warnings and errors are also added
+/// to `warnings`, so they can be reported once the build finishes.
+pub fn create_log_sink(
+    lua: &Lua,
+    warnings: Arc<Mutex<Vec<ScriptLogEntry>>>,
+) -> LuaResult<Function<'_>> {
+    lua.create_function(
+        move |lua, args: (Value<'_>, String, Option<String>, Option<String>, Option<u32>)| {
+            let (level, message, mod_name, module, line) = args;
+            let level: ScriptLogLevel = lua.from_value(level)?;
+            let entry = ScriptLogEntry { level, mod_name, module, line, message };
+            emit(&entry);
+            if entry.level >= ScriptLogLevel::Warn {
+                warnings.lock().unwrap().push(entry);
+            }
+            Ok(())
+        },
+    )
+}
@@ -1,16 +1,89 @@
+mod log;
+mod pdx_module;
+
+pub use log::{ScriptLogEntry, ScriptLogLevel};
+pub use pdx_module::PdxModule;
+
 use crate::{mods::LoadedMod, paths};
 use anyhow::*;
 use mlua::{
-    prelude::LuaResult, FromLua, FromLuaMulti, Function, Lua, LuaSerdeExt, StdLib, ToLua,
-    ToLuaMulti, UserData,
+    prelude::LuaResult, FromLua, FromLuaMulti, Function, HookTriggers, Lua, LuaSerdeExt, StdLib,
+    Table, ToLua, ToLuaMulti, UserData, Value,
 };
-use std::path::{Path, PathBuf};
+use serde::*;
+use std::{
+    fmt, fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// An error raised while compiling or running a script.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ScriptError {
+    /// The ID of the mod the error was raised in.
+    pub mod_name: String,
+    /// The module the error was raised in, relative to the mod's source or library directory.
+    pub module: String,
+    /// The line the error was raised at. For metalua modules, this is the line of the `.mlua`
+    /// source rather than of the Lua source generated from it.
+    pub line: Option<u32>,
+    pub message: String,
+    /// The Lua stack traceback, if the error was raised while the script was running.
+    pub traceback: Option<String>,
+}
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.mod_name, self.module)?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(traceback) = &self.traceback {
+            write!(f, "\n\n{}", traceback)?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for ScriptError {}
+
+/// The registry key of the module that runs mod scripts.
+const SCRIPT_LOADER_KEY: &str = "patchling_private.script_loader";
+/// The registry key of the module that wraps rule mirrors in change-recording proxies.
+pub(crate) const PROXY_KEY: &str = "patchling_private.proxy";
 
-// TODO: Logging.
+/// How many instructions are run between checks of a script's resource limits.
+const LIMIT_CHECK_INTERVAL: u32 = 1000;
 
-pub struct LuaContext(Lua);
+/// Limits on the resources a single script execution may use.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ScriptLimits {
+    /// The maximum number of Lua instructions a script may run.
+    pub instructions: Option<u64>,
+    /// The maximum number of bytes of memory a script may allocate and keep alive.
+    ///
+    /// LuaJIT has no allocator-level limit, so this is only checked every few instructions, and
+    /// bounds a script's memory use only approximately. A single large allocation made by a
+    /// library function, such as `string.rep` or `table.concat`, may exceed the limit before the
+    /// script is stopped.
+    pub memory: Option<usize>,
+}
+impl ScriptLimits {
+    fn is_limited(&self) -> bool {
+        self.instructions.is_some() || self.memory.is_some()
+    }
+}
+
+pub struct LuaContext {
+    lua: Lua,
+    limits: ScriptLimits,
+    warnings: Arc<Mutex<Vec<ScriptLogEntry>>>,
+}
 impl LuaContext {
-    pub fn new(lua_root: impl AsRef<Path>, mod_paths: &[LoadedMod]) -> Result<LuaContext> {
+    pub fn new(
+        lua_root: impl AsRef<Path>,
+        mod_paths: &[LoadedMod],
+        limits: ScriptLimits,
+    ) -> Result<LuaContext> {
         let lua_root = lua_root.as_ref().to_path_buf();
         let lua = unsafe {
             Lua::unsafe_new_with(
@@ -23,16 +96,21 @@ impl LuaContext {
                     | StdLib::IO,
             )
         };
-        let lua = LuaContext(lua);
+        let lua = LuaContext { lua, limits, warnings: Arc::new(Mutex::new(Vec::new())) };
 
-        let libs_path = lua.0.create_string(lua_root.display().to_string().as_bytes())?;
-        let mod_paths = lua.0.to_value(mod_paths);
+        let libs_path = lua.lua.create_string(lua_root.display().to_string().as_bytes())?;
+        let mod_paths = lua.lua.to_value(mod_paths)?;
+        let log_sink = log::create_log_sink(&lua.lua, lua.warnings.clone())?;
 
-        lua.0
+        let (script_loader, proxy): (Table<'_>, Table<'_>) = lua
+            .lua
             .load(include_str!("bootstrap_privileged.lua"))
             .set_name("@<intrinsic>/bootstrap_privileged.lua")?
-            .call::<_, ()>((libs_path, mod_paths))?;
-        lua.0
+            .call((libs_path, mod_paths, log_sink))?;
+        // privileged modules are hidden from `require`, so they are kept in the registry instead.
+        lua.lua.set_named_registry_value(SCRIPT_LOADER_KEY, script_loader)?;
+        lua.lua.set_named_registry_value(PROXY_KEY, proxy)?;
+        lua.lua
             .load(include_str!("bootstrap_metalua.lua"))
             .set_name("@<intrinsic>/bootstrap_metalua.lua")?
             .call::<_, ()>(())?;
@@ -49,27 +127,146 @@ impl LuaContext {
         func_module: &str,
         args: P,
     ) -> Result<R> {
-        let require = self.0.globals().get::<_, Function<'_>>("require")?;
+        let require = self.lua.globals().get::<_, Function<'_>>("require")?;
         let check_error: Function<'_> = require.call("patchling_private.check_error")?;
         let func: Function<'_> = require.call(func_module)?;
         let (res, err): (Option<R>, Option<String>) = check_error.bind(func)?.call(args)?;
         if let Some(e) = err { bail!("{}", e) } else { Ok(res.unwrap()) }
     }
 
-    pub fn execute_script(&self, path: PathBuf) -> Result<()> {
-        todo!()
+    /// Runs a script from one of a mod's source directories. Errors raised by the script are
+    /// returned as a [`ScriptError`].
+    pub fn execute_script(&self, loaded_mod: &LoadedMod, path: &Path) -> Result<()> {
+        let source_dirs = &loaded_mod.info.source_dirs;
+        let relative = match source_dirs.iter().find_map(|x| path.strip_prefix(x).ok()) {
+            Some(relative) => relative,
+            None => bail!(
+                "{} is not in a source directory of mod {}.",
+                path.display(),
+                loaded_mod.info.id
+            ),
+        };
+        let is_metalua = match path.extension().and_then(|x| x.to_str()) {
+            Some("lua") => false,
+            Some("mlua") => true,
+            _ => bail!("Scripts must be .lua or .mlua files: {}", path.display()),
+        };
+        let module_name = relative
+            .components()
+            .map(|x| x.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("Could not read script {}", path.display()))?;
+
+        let script_loader: Table<'_> = self.lua.named_registry_value(SCRIPT_LOADER_KEY)?;
+        let execute_script: Function<'_> = script_loader.get("execute_script")?;
+        let mod_id = loaded_mod.info.id.as_str();
+        let file_name = path.display().to_string();
+        let args = (mod_id, file_name, module_name.as_str(), source, is_metalua);
+
+        let exceeded = self.install_limits(mod_id)?;
+        let result = execute_script.call::<_, Value<'_>>(args);
+        self.lua.remove_hook();
+        let error = match result? {
+            Value::Nil => None,
+            error => Some(self.lua.from_value::<ScriptError>(error)?),
+        };
+
+        let exceeded = exceeded.lock().unwrap().take();
+        match (error, exceeded) {
+            (error, Some(message)) => {
+                // the script may have caught the error raised by the hook, so a limit being
+                // exceeded is reported even if the script went on to finish.
+                let mut error = error.unwrap_or(ScriptError {
+                    mod_name: mod_id.to_string(),
+                    module: module_name,
+                    line: None,
+                    message: String::new(),
+                    traceback: None,
+                });
+                error.mod_name = mod_id.to_string();
+                error.message = message;
+                Err(error.into())
+            }
+            (Some(error), None) => Err(error.into()),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Installs a hook that enforces the resource limits on a script from the given mod. The
+    /// returned value is set to a description of the limit if the script exceeds one, after which
+    /// the hook raises an error whenever it runs.
+    fn install_limits(&self, mod_id: &str) -> Result<Arc<Mutex<Option<String>>>> {
+        let exceeded = Arc::new(Mutex::new(None));
+        if !self.limits.is_limited() {
+            return Ok(exceeded);
+        }
+
+        let limits = self.limits;
+        let mod_id = mod_id.to_string();
+        let base_memory = self.lua.used_memory();
+        let mut instructions = 0u64;
+        let hook_exceeded = exceeded.clone();
+        let triggers = HookTriggers {
+            every_nth_instruction: Some(LIMIT_CHECK_INTERVAL),
+            ..Default::default()
+        };
+        self.lua.set_hook(triggers, move |lua, _| {
+            let mut exceeded = hook_exceeded.lock().unwrap();
+            if exceeded.is_none() {
+                instructions += LIMIT_CHECK_INTERVAL as u64;
+                if let Some(limit) = limits.instructions {
+                    if instructions > limit {
+                        *exceeded = Some(format!(
+                            "Script from mod {} exceeded the limit of {} instructions.",
+                            mod_id, limit,
+                        ));
+                    }
+                }
+                if let Some(limit) = limits.memory {
+                    // garbage is only collected once the limit seems to be exceeded, so only
+                    // memory that is still in use counts towards it.
+                    if lua.used_memory().saturating_sub(base_memory) > limit {
+                        lua.gc_collect()?;
+                        if lua.used_memory().saturating_sub(base_memory) > limit {
+                            *exceeded = Some(format!(
+                                "Script from mod {} exceeded the limit of {} bytes of memory.",
+                                mod_id, limit,
+                            ));
+                        }
+                    }
+                }
+            }
+            match &*exceeded {
+                Some(message) => Err(mlua::Error::RuntimeError(message.clone())),
+                None => Ok(()),
+            }
+        })?;
+        Ok(exceeded)
+    }
+
+    /// Returns the warnings and errors scripts have logged so far.
+    pub fn script_warnings(&self) -> Vec<ScriptLogEntry> {
+        self.warnings.lock().unwrap().clone()
     }
 
     pub fn compile_and_minify(&self, source: &str, name: &str) -> Result<String> {
         Ok(self.wrapped_execute("patchling_private.compile_and_minify", (source, name))?)
     }
 
+    pub fn lua(&self) -> &Lua {
+        &self.lua
+    }
+
     pub fn register_module(
         &self,
         name: &str,
         userdata: impl UserData + Send + 'static,
     ) -> Result<()> {
-        self.0.globals().set(self.0.create_string(name)?, self.0.create_userdata(userdata)?)?;
+        self.lua
+            .globals()
+            .set(self.lua.create_string(name)?, self.lua.create_userdata(userdata)?)?;
         Ok(())
     }
 }
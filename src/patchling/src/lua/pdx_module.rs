@@ -0,0 +1,64 @@
+use crate::pdx::{PdxBlock, PdxQuery};
+use mlua::{prelude::LuaString, LuaSerdeExt, UserData, UserDataMethods, Value};
+
+/// The name given to PDX literals parsed by scripts.
+const LITERAL_FILE_NAME: &str = "<pdx literal>";
+
+/// The `pdx` module available to scripts, for working with PDX trees.
+pub struct PdxModule;
+impl UserData for PdxModule {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        // Returns a list of the matching relations, and a list of their paths.
+        methods.add_function("query", |lua, (block, query): (Value<'_>, LuaString<'_>)| {
+            let block: PdxBlock = lua.from_value(block)?;
+            let query = PdxQuery::parse(query.to_str()?).map_err(mlua::Error::external)?;
+
+            let relations = lua.create_table()?;
+            let paths = lua.create_table()?;
+            for (i, found) in query.find(&block).into_iter().enumerate() {
+                relations.raw_set(i + 1, lua.to_value(found.relation)?)?;
+                paths.raw_set(i + 1, lua.to_value(&found.path)?)?;
+            }
+            Ok((relations, paths))
+        });
+
+        // Parses PDX source into the contents of a block. This is used by the `pdx` metalua
+        // extension to compile PDX literals.
+        methods.add_function("parse", |lua, source: LuaString<'_>| {
+            let block = PdxBlock::parse_file(LITERAL_FILE_NAME, source.as_bytes())
+                .map_err(mlua::Error::external)?;
+            lua.to_value(&block)
+        });
+
+        // Creates a relation from a tag, a Lua value and an optional relation type, storing the
+        // value according to its type. This is used for values spliced into PDX literals.
+        methods.add_function(
+            "relation",
+            |lua, args: (LuaString<'_>, Value<'_>, Option<LuaString<'_>>)| {
+                let (tag, value, relation_type) = args;
+                let (kind, value) = match value {
+                    Value::Integer(_) | Value::Number(_) => ("num", value),
+                    Value::String(_) => ("val", value),
+                    Value::Boolean(b) => {
+                        ("val", Value::String(lua.create_string(if b { "yes" } else { "no" })?))
+                    }
+                    Value::Table(_) => ("block", value),
+                    _ => {
+                        let message = format!(
+                            "A {} cannot be used as the value of a relation.",
+                            value.type_name()
+                        );
+                        return Err(mlua::Error::RuntimeError(message));
+                    }
+                };
+                let relation = lua.create_table()?;
+                relation.raw_set("tag", tag)?;
+                relation.raw_set(kind, value)?;
+                if let Some(relation_type) = relation_type {
+                    relation.raw_set("relation", relation_type)?;
+                }
+                Ok(relation)
+            },
+        );
+    }
+}
@@ -0,0 +1,312 @@
+use crate::pdx::{PdxBlock, PdxBlockContent, PdxEncoding, PdxRelationValue};
+use anyhow::*;
+use std::{ops::Range, sync::Arc};
+
+/// The location of a block member in the source of a [`PdxSourceFile`].
+#[derive(Clone, Debug)]
+pub struct PdxCstNode {
+    pub range: Range<usize>,
+    /// The syntax tree of this member's value, if it is a block.
+    pub block: Option<PdxCstBlock>,
+}
+
+/// The location of a block and its members in the source of a [`PdxSourceFile`].
+///
+/// Any part of a block's range that is not covered by one of its members is trivia, such as
+/// whitespace, comments or text that could not be parsed.
+#[derive(Clone, Debug)]
+pub struct PdxCstBlock {
+    pub range: Range<usize>,
+    pub members: Vec<PdxCstNode>,
+}
+
+/// A parsed PDX file that retains the comments and formatting of its source.
+///
+/// This allows a modified version of the file to be written back out, while leaving every part
+/// that was not changed byte-identical to the original.
+#[derive(Clone, Debug)]
+pub struct PdxSourceFile {
+    pub(crate) name: Arc<str>,
+    pub(crate) source: Arc<str>,
+    pub(crate) encoding: PdxEncoding,
+    pub(crate) contents: PdxBlock,
+    pub(crate) cst: PdxCstBlock,
+}
+impl PdxSourceFile {
+    /// Returns the name this file was parsed with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the source text of this file.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns the encoding this file was saved in.
+    pub fn encoding(&self) -> PdxEncoding {
+        self.encoding
+    }
+
+    /// Returns the parsed contents of this file.
+    pub fn contents(&self) -> &PdxBlock {
+        &self.contents
+    }
+
+    /// Returns the syntax tree of this file.
+    pub fn cst(&self) -> &PdxCstBlock {
+        &self.cst
+    }
+
+    /// Emits a new version of this file with the given contents, reusing the original source
+    /// text for every part of the file that is unchanged.
+    pub fn emit(&self, contents: &PdxBlock) -> String {
+        let root = &self.cst;
+        let mut out = String::with_capacity(self.source.len());
+        out.push_str(&self.source[..root.range.start]);
+        self.emit_members(&mut out, &self.contents, root, contents, root.range.clone(), 0);
+        out.push_str(&self.source[root.range.end..]);
+        out
+    }
+
+    /// Emits a new version of this file with the given contents, encoded in the same encoding as
+    /// the original file.
+    pub fn emit_bytes(&self, contents: &PdxBlock) -> Result<Vec<u8>> {
+        self.encoding.encode(&self.emit(contents))
+    }
+
+    /// Emits the members of a block, with `inner` being the range between its brackets.
+    fn emit_members(
+        &self,
+        out: &mut String,
+        old: &PdxBlock,
+        cst: &PdxCstBlock,
+        new: &PdxBlock,
+        inner: Range<usize>,
+        indent_level: usize,
+    ) {
+        let mut cursor = inner.start;
+        let mut old_idx = 0;
+        let mut last_node: Option<&PdxCstNode> = None;
+        for new_member in &new.contents {
+            match find_corresponding(&old.contents[old_idx..], new_member) {
+                Some(offset) => {
+                    for node in &cst.members[old_idx..old_idx + offset] {
+                        cursor = self.skip_member(out, cursor, node);
+                    }
+                    let idx = old_idx + offset;
+                    let node = &cst.members[idx];
+                    out.push_str(&self.source[cursor..node.range.start]);
+                    self.emit_member(out, &old.contents[idx], node, new_member, indent_level);
+                    cursor = node.range.end;
+                    old_idx = idx + 1;
+                    last_node = Some(node);
+                }
+                None => {
+                    if let Some(node) = last_node {
+                        if cursor == node.range.end {
+                            cursor = self.take_line_trivia(out, cursor, inner.end);
+                        }
+                    }
+
+                    let indent = match last_node.or_else(|| cst.members.get(old_idx)) {
+                        Some(node) => self.line_indent(node.range.start),
+                        None if indent_level == 0 => Some(""),
+                        None => None,
+                    };
+                    match indent {
+                        Some(indent) => {
+                            if !out.is_empty() && !out.ends_with('\n') {
+                                out.push('\n');
+                            }
+                            out.push_str(indent);
+                        }
+                        None => out.push(' '),
+                    }
+                    out.push_str(&new_member.display_at(indent_level).to_string());
+                }
+            }
+        }
+        for node in &cst.members[old_idx..] {
+            cursor = self.skip_member(out, cursor, node);
+        }
+        out.push_str(&self.source[cursor..inner.end]);
+    }
+
+    /// Emits a single block member that corresponds to one in the original source.
+    fn emit_member(
+        &self,
+        out: &mut String,
+        old: &PdxBlockContent,
+        node: &PdxCstNode,
+        new: &PdxBlockContent,
+        indent_level: usize,
+    ) {
+        if old == new {
+            out.push_str(&self.source[node.range.clone()]);
+            return;
+        }
+
+        if let (PdxBlockContent::Relation(old_rel), PdxBlockContent::Relation(new_rel)) = (old, new)
+        {
+            if let (
+                PdxRelationValue::Block(old_block),
+                PdxRelationValue::Block(new_block),
+                Some(block_node),
+            ) = (&old_rel.value, &new_rel.value, &node.block)
+            {
+                if old_rel.tag == new_rel.tag && old_rel.relation == new_rel.relation {
+                    // only the contents of the block changed, so we keep the brackets.
+                    let range = &block_node.range;
+                    let closed = self.source[range.clone()].ends_with('}');
+                    let inner_end = if closed { range.end - 1 } else { range.end };
+
+                    out.push_str(&self.source[node.range.start..range.start + 1]);
+                    self.emit_members(
+                        out,
+                        old_block,
+                        block_node,
+                        new_block,
+                        range.start + 1..inner_end,
+                        indent_level + 1,
+                    );
+                    out.push_str(&self.source[inner_end..node.range.end]);
+                    return;
+                }
+            }
+        }
+
+        out.push_str(&new.display_at(indent_level).to_string());
+    }
+
+    /// Skips a deleted block member, keeping any comments that precede it.
+    fn skip_member(&self, out: &mut String, cursor: usize, node: &PdxCstNode) -> usize {
+        let trivia = &self.source[cursor..node.range.start];
+        if trivia.contains('#') {
+            out.push_str(trivia);
+        }
+        node.range.end
+    }
+
+    /// Emits the rest of the current line if it only contains whitespace or a comment.
+    fn take_line_trivia(&self, out: &mut String, cursor: usize, end: usize) -> usize {
+        let rest = &self.source[cursor..end];
+        if let Some(line_end) = rest.find('\n') {
+            let line = rest[..line_end].trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                out.push_str(&rest[..line_end]);
+                return cursor + line_end;
+            }
+        }
+        cursor
+    }
+
+    /// Returns the indentation of the line a position is on, if only whitespace precedes it.
+    fn line_indent(&self, pos: usize) -> Option<&str> {
+        let line_start = self.source[..pos].rfind('\n').map_or(0, |x| x + 1);
+        let indent = &self.source[line_start..pos];
+        if indent.chars().all(|x| x == ' ' || x == '\t') {
+            Some(indent)
+        } else {
+            None
+        }
+    }
+}
+
+/// Finds the member of the original block that corresponds to a member of a new block.
+fn find_corresponding(old: &[PdxBlockContent], new: &PdxBlockContent) -> Option<usize> {
+    if old.first() == Some(new) {
+        return Some(0);
+    }
+    old.iter().position(|x| x == new).or_else(|| {
+        old.iter().position(|x| match (x, new) {
+            (PdxBlockContent::Relation(a), PdxBlockContent::Relation(b)) => a.tag == b.tag,
+            _ => false,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdx::PdxRelation;
+
+    const SOURCE: &str = "\
+# A comment at the top of the file.
+@tier3cost = 250
+
+tech_a = {
+\tcost = @tier3cost # the cost is shared
+\t\"quoted key\" = \"quoted value\"
+
+\tlist = { a   b c }    # odd spacing
+\tpotential = {
+\t\tnot = { has_tech = \"tech_b\" }
+\t}
+}
+
+# A comment between rules.
+tech_b = { cost = 100 weight = 5 }
+";
+
+    fn relation_mut<'a>(block: &'a mut PdxBlock, tag: &str) -> &'a mut PdxRelation {
+        let found = block.contents.iter_mut().find_map(|x| match x {
+            PdxBlockContent::Relation(rel) if &*rel.tag == tag => Some(rel),
+            _ => None,
+        });
+        found.unwrap()
+    }
+
+    fn block_mut<'a>(block: &'a mut PdxBlock, tag: &str) -> &'a mut PdxBlock {
+        match &mut relation_mut(block, tag).value {
+            PdxRelationValue::Block(block) => block,
+            _ => panic!("{} is not a block", tag),
+        }
+    }
+
+    #[test]
+    fn round_trips_unchanged_files() {
+        let sources = [
+            SOURCE,
+            "",
+            "# only a comment",
+            "a = b",
+            "\n\n  a = \"b c\"   \n\n",
+            "a = { }\r\nb = {\r\n\tc = d\r\n}\r\n",
+            "a = { b = c # unterminated comment",
+        ];
+        for source in &sources {
+            // files with syntax errors must round-trip too.
+            let (file, _) = PdxSourceFile::parse_recovering("test.txt", source.as_bytes()).unwrap();
+            assert_eq!(file.emit(file.contents()), *source);
+        }
+    }
+
+    #[test]
+    fn round_trips_encodings() {
+        let sources: [&[u8]; 2] =
+            [b"\xEF\xBB\xBFname = \"Caf\xC3\xA9\" # comment\n", b"name = \"Caf\xE9\" # comment\n"];
+        for source in &sources {
+            let file = PdxSourceFile::parse("test.txt", source).unwrap();
+            assert_eq!(file.emit_bytes(file.contents()).unwrap(), *source);
+        }
+    }
+
+    #[test]
+    fn edits_leave_the_rest_of_the_file_unchanged() {
+        let file = PdxSourceFile::parse("test.txt", SOURCE.as_bytes()).unwrap();
+
+        let mut contents = file.contents().clone();
+        relation_mut(block_mut(&mut contents, "tech_b"), "cost").value =
+            PdxRelationValue::Numeric(150.0);
+        let expected = SOURCE.replace("cost = 100", "cost = 150");
+        assert_eq!(file.emit(&contents), expected);
+
+        let mut contents = file.contents().clone();
+        let potential = block_mut(block_mut(&mut contents, "tech_a"), "potential");
+        relation_mut(block_mut(potential, "not"), "has_tech").value =
+            PdxRelationValue::String("tech_c".into());
+        let expected = SOURCE.replace("has_tech = \"tech_b\"", "has_tech = tech_c");
+        assert_eq!(file.emit(&contents), expected);
+    }
+}
@@ -0,0 +1,344 @@
+use crate::pdx::{PdxBlock, PdxBlockContent, PdxRelation, PdxRelationValue};
+use serde::*;
+use std::{borrow::Borrow, cmp::Ordering, collections::HashMap, fmt, sync::Arc};
+
+/// The structural differences between two PDX blocks.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct PdxDiff {
+    pub entries: Vec<PdxDiffEntry>,
+}
+
+/// A single difference between two PDX blocks.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PdxDiffEntry {
+    /// The chain of tags leading to the block the change was made in.
+    pub path: Vec<Arc<str>>,
+    pub change: PdxDiffChange,
+}
+
+/// A change made to a member of a block. Indexes are positions in the block's contents.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PdxDiffChange {
+    /// A member was added to the new block.
+    Inserted { index: usize, content: PdxBlockContent },
+    /// A member of the old block was removed.
+    Deleted { index: usize, content: PdxBlockContent },
+    /// A member was moved relative to the members around it. It may also have been changed,
+    /// in which case the changes to it are reported separately.
+    Moved { from: usize, to: usize, content: PdxBlockContent },
+    /// The value or relation type of a relation was changed. Changes inside blocks are reported
+    /// as changes to the members of the block instead.
+    Changed { index: usize, old: PdxRelation, new: PdxRelation },
+}
+
+impl PdxDiff {
+    /// Returns whether the two blocks were identical.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl PdxBlock {
+    /// Computes the structural differences between this block and a newer version of it.
+    ///
+    /// Relations are matched up by their tag, and repeated keys and bare values are matched up
+    /// with identical members first, then in order of appearance.
+    pub fn diff(&self, new: &PdxBlock) -> PdxDiff {
+        let mut entries = Vec::new();
+        diff_blocks(&mut Vec::new(), self, new, &mut entries);
+        PdxDiff { entries }
+    }
+}
+
+fn member_key(content: &PdxBlockContent) -> (bool, &str) {
+    match content {
+        PdxBlockContent::Relation(rel) => (true, &rel.tag),
+        PdxBlockContent::String(str) => (false, str),
+    }
+}
+
+/// Finds the member of the new block each member of the old block corresponds to.
+pub(crate) fn pair_members<A, B>(old: &[A], new: &[B]) -> Vec<Option<usize>>
+where
+    A: Borrow<PdxBlockContent>,
+    B: Borrow<PdxBlockContent>,
+{
+    let old: Vec<&PdxBlockContent> = old.iter().map(|x| x.borrow()).collect();
+    let new: Vec<&PdxBlockContent> = new.iter().map(|x| x.borrow()).collect();
+    let mut by_key: HashMap<_, Vec<usize>> = HashMap::new();
+    for (i, content) in new.iter().enumerate() {
+        by_key.entry(member_key(content)).or_default().push(i);
+    }
+
+    let mut old_to_new = vec![None; old.len()];
+    let mut taken = vec![false; new.len()];
+    // identical members are matched first, so repeated keys are not needlessly reported as
+    // changed when one of them is removed.
+    for (i, content) in old.iter().enumerate() {
+        if let Some(candidates) = by_key.get(&member_key(content)) {
+            if let Some(&j) = candidates.iter().find(|&&j| !taken[j] && new[j] == *content) {
+                old_to_new[i] = Some(j);
+                taken[j] = true;
+            }
+        }
+    }
+    for (i, content) in old.iter().enumerate() {
+        if old_to_new[i].is_none() && matches!(content, PdxBlockContent::Relation(_)) {
+            if let Some(candidates) = by_key.get(&member_key(content)) {
+                if let Some(&j) = candidates.iter().find(|&&j| !taken[j]) {
+                    old_to_new[i] = Some(j);
+                    taken[j] = true;
+                }
+            }
+        }
+    }
+    old_to_new
+}
+
+/// Finds which members kept their relative order, as the longest increasing subsequence of their
+/// new positions. Every other member is reported as moved.
+fn find_unmoved(old_to_new: &[Option<usize>]) -> Vec<bool> {
+    let paired: Vec<(usize, usize)> =
+        old_to_new.iter().enumerate().filter_map(|(i, j)| j.map(|j| (i, j))).collect();
+
+    // `tails[k]` is the index into `paired` of the smallest tail of an increasing subsequence
+    // of length `k + 1`.
+    let mut tails: Vec<usize> = Vec::new();
+    let mut prev = vec![None; paired.len()];
+    for (idx, &(_, j)) in paired.iter().enumerate() {
+        let pos = tails
+            .binary_search_by(|&t| if paired[t].1 < j { Ordering::Less } else { Ordering::Greater })
+            .unwrap_err();
+        if pos > 0 {
+            prev[idx] = Some(tails[pos - 1]);
+        }
+        if pos == tails.len() {
+            tails.push(idx);
+        } else {
+            tails[pos] = idx;
+        }
+    }
+
+    let mut unmoved = vec![false; old_to_new.len()];
+    let mut cursor = tails.last().copied();
+    while let Some(idx) = cursor {
+        unmoved[paired[idx].0] = true;
+        cursor = prev[idx];
+    }
+    unmoved
+}
+
+fn diff_blocks(
+    path: &mut Vec<Arc<str>>,
+    old: &PdxBlock,
+    new: &PdxBlock,
+    out: &mut Vec<PdxDiffEntry>,
+) {
+    let old_to_new = pair_members(&old.contents, &new.contents);
+    let unmoved = find_unmoved(&old_to_new);
+    let mut new_to_old = vec![None; new.contents.len()];
+    for (i, j) in old_to_new.iter().enumerate() {
+        if let Some(j) = j {
+            new_to_old[*j] = Some(i);
+        }
+    }
+
+    for (i, content) in old.contents.iter().enumerate() {
+        if old_to_new[i].is_none() {
+            push_entry(out, path, PdxDiffChange::Deleted { index: i, content: content.clone() });
+        }
+    }
+    for (j, content) in new.contents.iter().enumerate() {
+        match new_to_old[j] {
+            None => push_entry(out, path, PdxDiffChange::Inserted {
+                index: j,
+                content: content.clone(),
+            }),
+            Some(i) => {
+                if !unmoved[i] {
+                    push_entry(out, path, PdxDiffChange::Moved {
+                        from: i,
+                        to: j,
+                        content: content.clone(),
+                    });
+                }
+                if old.contents[i] != *content {
+                    diff_members(path, j, &old.contents[i], content, out);
+                }
+            }
+        }
+    }
+}
+
+fn push_entry(out: &mut Vec<PdxDiffEntry>, path: &[Arc<str>], change: PdxDiffChange) {
+    out.push(PdxDiffEntry { path: path.to_vec(), change });
+}
+
+fn diff_members(
+    path: &mut Vec<Arc<str>>,
+    index: usize,
+    old: &PdxBlockContent,
+    new: &PdxBlockContent,
+    out: &mut Vec<PdxDiffEntry>,
+) {
+    // members are only paired if they are identical, or relations with the same tag.
+    if let (PdxBlockContent::Relation(old_rel), PdxBlockContent::Relation(new_rel)) = (old, new) {
+        if let (PdxRelationValue::Block(old_block), PdxRelationValue::Block(new_block)) =
+            (&old_rel.value, &new_rel.value)
+        {
+            if old_rel.relation == new_rel.relation {
+                path.push(new_rel.tag.clone());
+                diff_blocks(path, old_block, new_block, out);
+                path.pop();
+                return;
+            }
+        }
+        let change = PdxDiffChange::Changed { index, old: old_rel.clone(), new: new_rel.clone() };
+        push_entry(out, path, change);
+    }
+}
+
+impl fmt::Display for PdxDiffEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match &self.change {
+            PdxDiffChange::Inserted { .. } => "+",
+            PdxDiffChange::Deleted { .. } => "-",
+            PdxDiffChange::Moved { .. } => ">",
+            PdxDiffChange::Changed { .. } => "~",
+        };
+        f.write_str(symbol)?;
+        f.write_str(" ")?;
+        if !self.path.is_empty() {
+            write!(f, "{}: ", self.path.join("."))?;
+        }
+        match &self.change {
+            PdxDiffChange::Inserted { content, .. } | PdxDiffChange::Deleted { content, .. } => {
+                fmt::Display::fmt(content, f)
+            }
+            PdxDiffChange::Moved { from, to, content } => {
+                write!(f, "{} (moved from position {} to {})", content, from, to)
+            }
+            PdxDiffChange::Changed { old, new, .. } => write!(f, "{} -> {}", old, new),
+        }
+    }
+}
+
+impl fmt::Display for PdxDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff_src(old: &str, new: &str) -> PdxDiff {
+        let old = PdxBlock::parse_file("old.txt", old.as_bytes()).unwrap();
+        let new = PdxBlock::parse_file("new.txt", new.as_bytes()).unwrap();
+        old.diff(&new)
+    }
+
+    fn lines(diff: &PdxDiff) -> Vec<String> {
+        diff.entries.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn identical_blocks_have_no_differences() {
+        assert!(diff_src("a = 1 b = { c = d }", "a = 1 b = { c = d }").is_empty());
+    }
+
+    #[test]
+    fn finds_insertions() {
+        let diff = diff_src("a = 1 b = 2", "a = 1 x = 0 b = 2");
+        assert_eq!(lines(&diff), vec!["+ x = 0"]);
+        assert!(matches!(diff.entries[0].change, PdxDiffChange::Inserted { index: 1, .. }));
+    }
+
+    #[test]
+    fn finds_deletions() {
+        let diff = diff_src("a = 1 b = 2 c = 3", "a = 1 c = 3");
+        assert_eq!(lines(&diff), vec!["- b = 2"]);
+        assert!(matches!(diff.entries[0].change, PdxDiffChange::Deleted { index: 1, .. }));
+    }
+
+    #[test]
+    fn finds_moves() {
+        // only the member that left the longest run of members in order is reported as moved.
+        let diff = diff_src("a = 1 b = 2 c = 3", "c = 3 a = 1 b = 2");
+        assert_eq!(diff.entries.len(), 1);
+        assert!(matches!(diff.entries[0].change, PdxDiffChange::Moved { from: 2, to: 0, .. }));
+
+        // a member that was moved and changed is reported as both.
+        let diff = diff_src("a = 1 b = 2 c = 3", "c = 4 a = 1 b = 2");
+        assert_eq!(diff.entries.len(), 2);
+        assert!(matches!(diff.entries[0].change, PdxDiffChange::Moved { from: 2, to: 0, .. }));
+        assert!(matches!(diff.entries[1].change, PdxDiffChange::Changed { index: 0, .. }));
+    }
+
+    #[test]
+    fn finds_value_changes() {
+        let diff = diff_src("cost = 100", "cost = 200");
+        assert_eq!(lines(&diff), vec!["~ cost = 100 -> cost = 200"]);
+
+        let diff = diff_src(
+            "cost = { energy = 100 minerals = 5 }",
+            "cost = { energy = 200 minerals = 5 }",
+        );
+        assert_eq!(diff.entries.len(), 1);
+        assert_eq!(diff.entries[0].path, vec![Arc::from("cost")]);
+        assert!(matches!(diff.entries[0].change, PdxDiffChange::Changed { index: 0, .. }));
+
+        // changing the relation type is a change even for blocks.
+        let diff = diff_src("a = { b = c }", "a > { b = c }");
+        assert_eq!(diff.entries.len(), 1);
+        assert!(diff.entries[0].path.is_empty());
+    }
+
+    #[test]
+    fn pairs_repeated_keys() {
+        // identical members are paired first, so removing one repeated key is a deletion rather
+        // than a change to every member after it.
+        let diff = diff_src("modifier = { a = 1 } modifier = { b = 1 }", "modifier = { b = 1 }");
+        assert_eq!(diff.entries.len(), 1);
+        assert!(matches!(diff.entries[0].change, PdxDiffChange::Deleted { index: 0, .. }));
+
+        // the remaining repeated keys are paired in order.
+        let diff = diff_src(
+            "modifier = { a = 1 } modifier = { b = 1 }",
+            "modifier = { a = 2 } modifier = { b = 2 }",
+        );
+        let paths: Vec<_> = diff.entries.iter().map(|x| x.path.clone()).collect();
+        assert_eq!(paths, vec![vec![Arc::from("modifier")], vec![Arc::from("modifier")]]);
+        assert!(matches!(diff.entries[0].change, PdxDiffChange::Changed { index: 0, .. }));
+
+        // bare values are only paired with identical values.
+        let diff = diff_src("list = { a b a }", "list = { a b }");
+        assert_eq!(lines(&diff), vec!["- list: a"]);
+    }
+
+    #[test]
+    fn pair_members_prefers_identical_members() {
+        let parse = |src: &str| PdxBlock::parse_file("test.txt", src.as_bytes()).unwrap().contents;
+        let old = parse("a = 1 a = 2 b = 3");
+        let new = parse("a = 2 b = 4 a = 5");
+        assert_eq!(pair_members(&old, &new), vec![Some(2), Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn find_unmoved_keeps_the_longest_ordered_run() {
+        assert_eq!(find_unmoved(&[]), Vec::<bool>::new());
+        assert_eq!(find_unmoved(&[Some(0), Some(1), Some(2)]), vec![true, true, true]);
+        assert_eq!(find_unmoved(&[Some(2), Some(0), Some(1)]), vec![false, true, true]);
+        assert_eq!(find_unmoved(&[Some(0), None, Some(2), Some(1)]), vec![
+            true, false, false, true
+        ]);
+        assert_eq!(find_unmoved(&[Some(3), Some(2), Some(1), Some(0)]), vec![
+            false, false, false, true
+        ]);
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::*;
+use serde::*;
+use std::borrow::Cow;
+
+/// The characters Windows-1252 maps the bytes `0x80` to `0x9F` to. Bytes that are undefined in
+/// Windows-1252 are mapped to the corresponding C1 control characters, like Windows does.
+const CP1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// The text encoding a PDX file is saved in.
+#[derive(Serialize, Deserialize, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum PdxEncoding {
+    /// UTF-8 without a byte order mark.
+    Utf8,
+    /// UTF-8 with a byte order mark.
+    Utf8Bom,
+    /// Windows-1252, which is mostly a superset of Latin-1.
+    Windows1252,
+    /// Windows-1252 after a UTF-8 byte order mark, as written by editors that add a byte order
+    /// mark without converting the rest of the file.
+    Windows1252Bom,
+}
+impl PdxEncoding {
+    /// Detects the encoding of a file, and decodes it. Any byte order mark is removed.
+    ///
+    /// Files are treated as UTF-8 if they are valid UTF-8, and as Windows-1252 if they are not.
+    /// Files with a byte order mark that are not valid UTF-8 are decoded as Windows-1252 after
+    /// the byte order mark, so no character is lost.
+    pub fn decode(data: &[u8]) -> (Cow<'_, str>, PdxEncoding) {
+        let (data, has_bom) = match data.strip_prefix(UTF8_BOM) {
+            Some(data) => (data, true),
+            None => (data, false),
+        };
+        match std::str::from_utf8(data) {
+            Ok(str) if has_bom => (Cow::Borrowed(str), PdxEncoding::Utf8Bom),
+            Ok(str) => (Cow::Borrowed(str), PdxEncoding::Utf8),
+            Err(_) => {
+                let str = data.iter().map(|&x| decode_cp1252(x)).collect::<String>();
+                let encoding =
+                    if has_bom { PdxEncoding::Windows1252Bom } else { PdxEncoding::Windows1252 };
+                (Cow::Owned(str), encoding)
+            }
+        }
+    }
+
+    /// Encodes text in this encoding.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>> {
+        match self {
+            PdxEncoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            PdxEncoding::Utf8Bom => {
+                let mut data = UTF8_BOM.to_vec();
+                data.extend_from_slice(text.as_bytes());
+                Ok(data)
+            }
+            PdxEncoding::Windows1252 | PdxEncoding::Windows1252Bom => {
+                let mut data = Vec::with_capacity(text.len() + UTF8_BOM.len());
+                if *self == PdxEncoding::Windows1252Bom {
+                    data.extend_from_slice(UTF8_BOM);
+                }
+                for ch in text.chars() {
+                    match encode_cp1252(ch) {
+                        Some(byte) => data.push(byte),
+                        None => bail!("Character {:?} cannot be encoded in Windows-1252.", ch),
+                    }
+                }
+                Ok(data)
+            }
+        }
+    }
+}
+
+fn decode_cp1252(byte: u8) -> char {
+    match byte {
+        0x80..=0x9F => CP1252_HIGH[(byte - 0x80) as usize],
+        _ => byte as char,
+    }
+}
+
+fn encode_cp1252(ch: char) -> Option<u8> {
+    match ch as u32 {
+        0x00..=0x7F | 0xA0..=0xFF => Some(ch as u8),
+        _ => CP1252_HIGH.iter().position(|x| *x == ch).map(|x| x as u8 + 0x80),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_utf8() {
+        let (text, encoding) = PdxEncoding::decode("name = \"Ελλάδα\"".as_bytes());
+        assert_eq!(encoding, PdxEncoding::Utf8);
+        assert_eq!(text, "name = \"Ελλάδα\"");
+
+        let (text, encoding) = PdxEncoding::decode(b"\xEF\xBB\xBFkey = value");
+        assert_eq!(encoding, PdxEncoding::Utf8Bom);
+        assert_eq!(text, "key = value");
+    }
+
+    #[test]
+    fn cp1252_round_trip() {
+        let data = b"name = \"Caf\xE9 \x80 \x96 \x9F \x81\"";
+        let (text, encoding) = PdxEncoding::decode(data);
+        assert_eq!(encoding, PdxEncoding::Windows1252);
+        assert_eq!(text, "name = \"Café € – Ÿ \u{81}\"");
+        assert_eq!(encoding.encode(&text).unwrap(), data);
+    }
+
+    #[test]
+    fn cp1252_every_byte_round_trips() {
+        let data: Vec<u8> = (0..=255).collect();
+        let (text, encoding) = PdxEncoding::decode(&data);
+        assert_eq!(encoding, PdxEncoding::Windows1252);
+        assert_eq!(encoding.encode(&text).unwrap(), data);
+    }
+
+    #[test]
+    fn invalid_utf8_after_bom_round_trips() {
+        let data = b"\xEF\xBB\xBFname = Caf\xE9";
+        let (text, encoding) = PdxEncoding::decode(data);
+        assert_eq!(encoding, PdxEncoding::Windows1252Bom);
+        assert_eq!(text, "name = Café");
+        assert!(!text.contains('\u{FFFD}'));
+        assert_eq!(encoding.encode(&text).unwrap(), data);
+    }
+
+    #[test]
+    fn cp1252_rejects_unencodable_characters() {
+        assert!(PdxEncoding::Windows1252.encode("日本").is_err());
+    }
+}
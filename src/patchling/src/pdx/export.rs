@@ -1,4 +1,4 @@
-use crate::pdx::model::*;
+use crate::pdx::{model::*, parser::parse_date};
 use std::fmt::*;
 
 impl Display for PdxRelationType {
@@ -15,37 +15,50 @@ impl Display for PdxRelationType {
     }
 }
 
-struct DisplayStr<'a>(&'a str);
-impl<'a> Display for DisplayStr<'a> {
+impl Display for PdxColorSpace {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        let mut requires_escape = false;
-        for ch in self.0.chars() {
-            match ch {
-                'a'..='z' | 'A'..='Z' | '0'..='9' => {}
-                '_' | '.' | '-' | ':' | ';' | '\'' | '[' | ']' | '@' | '+' | '`' | '%' | '/'
-                | '!' | ',' | '<' | '>' | '?' | '$' | 'š' | 'Š' | '’' | '|' | '^' | '*' | '&' =>
-                    {}
-                _ => {
-                    requires_escape = true;
-                    break;
-                }
+        match self {
+            PdxColorSpace::Rgb => f.write_str("rgb"),
+            PdxColorSpace::Hsv => f.write_str("hsv"),
+            PdxColorSpace::Hsv360 => f.write_str("hsv360"),
+        }
+    }
+}
+
+fn fmt_str(str: &str, force_quotes: bool, f: &mut Formatter<'_>) -> Result {
+    let mut requires_escape = force_quotes || str.is_empty();
+    for ch in str.chars() {
+        match ch {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => {}
+            '_' | '.' | '-' | ':' | ';' | '\'' | '[' | ']' | '@' | '+' | '`' | '%' | '/' | '!'
+            | ',' | '<' | '>' | '?' | '$' | 'š' | 'Š' | '’' | '|' | '^' | '*' | '&' => {}
+            _ => {
+                requires_escape = true;
+                break;
             }
         }
+    }
 
-        if requires_escape {
-            f.write_str("\"")?;
-            for ch in self.0.chars() {
-                match ch {
-                    '\\' => f.write_str("\\")?,
-                    '\"' => f.write_str("\"")?,
-                    _ => f.write_char(ch)?,
-                }
+    if requires_escape {
+        f.write_str("\"")?;
+        for ch in str.chars() {
+            match ch {
+                '\\' => f.write_str("\\\\")?,
+                '\"' => f.write_str("\\\"")?,
+                _ => f.write_char(ch)?,
             }
-            f.write_str("\"")?;
-            Ok(())
-        } else {
-            f.write_str(self.0)
         }
+        f.write_str("\"")?;
+        Ok(())
+    } else {
+        f.write_str(str)
+    }
+}
+
+struct DisplayStr<'a>(&'a str);
+impl<'a> Display for DisplayStr<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        fmt_str(self.0, false, f)
     }
 }
 
@@ -66,10 +79,19 @@ impl<'a> Display for PdxRelationValueDisplay<'a> {
                 },
                 f,
             ),
-            PdxRelationValue::String(str) => Display::fmt(&DisplayStr(str.as_ref()), f),
+            // strings that look like dates must be quoted, or they would be parsed back as dates.
+            PdxRelationValue::String(str) => fmt_str(str, parse_date(str).is_some(), f),
             PdxRelationValue::Numeric(num) => Display::fmt(num, f),
             PdxRelationValue::Variable(var) => write!(f, "@{}", var),
             PdxRelationValue::VariableExpr(expr) => write!(f, "@\\[{}]", expr),
+            PdxRelationValue::Color { space, components } => {
+                write!(f, "{} {{ ", space)?;
+                for component in components {
+                    write!(f, "{} ", component)?;
+                }
+                f.write_char('}')
+            }
+            PdxRelationValue::Date { y, m, d } => write!(f, "{}.{:02}.{:02}", y, m, d),
         }
     }
 }
@@ -85,7 +107,7 @@ impl Display for PdxRelationValue {
 
 impl Display for PdxRelation {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        write!(f, "{} {} {}", self.tag, self.relation, self.value)
+        write!(f, "{} {} {}", DisplayStr(&self.tag), self.relation, self.value)
     }
 }
 
@@ -98,6 +120,34 @@ impl Display for PdxBlockContent {
     }
 }
 
+struct PdxBlockContentDisplay<'a> {
+    content: &'a PdxBlockContent,
+    indent_level: usize,
+    pretty_print: bool,
+}
+impl<'a> Display for PdxBlockContentDisplay<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self.content {
+            PdxBlockContent::Relation(rel) => {
+                let value = PdxRelationValueDisplay {
+                    rel: &rel.value,
+                    indent_level: self.indent_level + 1,
+                    pretty_print: self.pretty_print,
+                };
+                write!(f, "{} {} {}", DisplayStr(&rel.tag), rel.relation, value)
+            }
+            PdxBlockContent::String(str) => Display::fmt(&DisplayStr(str.as_ref()), f),
+        }
+    }
+}
+
+impl PdxBlockContent {
+    /// Pretty prints this block member, as if it were in a block nested to a given depth.
+    pub(crate) fn display_at(&self, indent_level: usize) -> impl Display + '_ {
+        PdxBlockContentDisplay { content: self, indent_level, pretty_print: true }
+    }
+}
+
 struct Indent(usize);
 impl Display for Indent {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
@@ -129,17 +179,14 @@ impl<'a> Display for PdxBlockDisplay<'a> {
                 Display::fmt(&Indent(self.indent_level), f)?;
             }
 
-            match line {
-                PdxBlockContent::Relation(rel) => {
-                    let value = PdxRelationValueDisplay {
-                        rel: &rel.value,
-                        indent_level: self.indent_level + 1,
-                        pretty_print: self.pretty_print,
-                    };
-                    write!(f, "{} {} {}", rel.tag, rel.relation, value)?;
-                }
-                PdxBlockContent::String(str) => Display::fmt(&DisplayStr(str.as_ref()), f)?,
-            }
+            Display::fmt(
+                &PdxBlockContentDisplay {
+                    content: line,
+                    indent_level: self.indent_level,
+                    pretty_print: self.pretty_print,
+                },
+                f,
+            )?;
 
             if self.pretty_print {
                 f.write_char('\n')?;
@@ -176,3 +223,105 @@ impl PdxBlock {
         PdxBlockDisplay { block: self, indent_level: 0, pretty_print, outer_braces }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdx::PdxBlock;
+
+    fn parse_value(src: &str) -> PdxRelationValue {
+        let block = PdxBlock::parse_file("test.txt", src.as_bytes()).unwrap();
+        match block.contents.into_iter().next() {
+            Some(PdxBlockContent::Relation(rel)) => rel.value,
+            content => panic!("expected a relation, found {:?}", content),
+        }
+    }
+
+    fn round_trip(src: &str) -> String {
+        PdxBlock::parse_file("test.txt", src.as_bytes())
+            .unwrap()
+            .display_file(false, false)
+            .to_string()
+            .trim_end()
+            .to_string()
+    }
+
+    #[test]
+    fn parses_colors() {
+        assert_eq!(parse_value("color = rgb { 255 128 0 }"), PdxRelationValue::Color {
+            space: PdxColorSpace::Rgb,
+            components: vec![255.0, 128.0, 0.0],
+        });
+        assert_eq!(parse_value("color = hsv { 0.5 1 0.25 }"), PdxRelationValue::Color {
+            space: PdxColorSpace::Hsv,
+            components: vec![0.5, 1.0, 0.25],
+        });
+        assert_eq!(parse_value("color = hsv360{ 300 50 100 }"), PdxRelationValue::Color {
+            space: PdxColorSpace::Hsv360,
+            components: vec![300.0, 50.0, 100.0],
+        });
+
+        // a color space keyword without components, or in quotes, is a plain string.
+        assert_eq!(parse_value("a = rgb b = c"), PdxRelationValue::String("rgb".into()));
+        assert_eq!(parse_value(r#"a = "rgb""#), PdxRelationValue::String("rgb".into()));
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_value("start = 2200.1.1"), PdxRelationValue::Date { y: 2200, m: 1, d: 1 });
+        assert_eq!(parse_value("start = 1444.11.11"), PdxRelationValue::Date {
+            y: 1444,
+            m: 11,
+            d: 11
+        });
+        assert_eq!(parse_value("start = -50.03.05"), PdxRelationValue::Date { y: -50, m: 3, d: 5 });
+
+        // out of range or malformed dates, and quoted dates, are plain strings.
+        for src in &["a = 2200.13.1", "a = 2200.1.32", "a = 2200.0.1", "a = 1.2.3.4"] {
+            assert!(matches!(parse_value(src), PdxRelationValue::String(_)), "{}", src);
+        }
+        assert_eq!(parse_value(r#"a = "2200.1.1""#), PdxRelationValue::String("2200.1.1".into()));
+    }
+
+    #[test]
+    fn exports_colors() {
+        assert_eq!(round_trip("color = rgb { 255 128 0 }"), "color = rgb { 255 128 0 }");
+        assert_eq!(round_trip("color = hsv360 { 300 50 100 }"), "color = hsv360 { 300 50 100 }");
+        assert_eq!(round_trip("color = hsv { 0.5 1 0.25 }"), "color = hsv { 0.5 1 0.25 }");
+    }
+
+    #[test]
+    fn exports_dates() {
+        // months and days are padded to two digits.
+        assert_eq!(PdxRelationValue::Date { y: 2200, m: 1, d: 5 }.to_string(), "2200.01.05");
+        assert_eq!(PdxRelationValue::Date { y: 1444, m: 11, d: 11 }.to_string(), "1444.11.11");
+        assert_eq!(round_trip("start = 2200.1.1"), "start = 2200.01.01");
+    }
+
+    #[test]
+    fn quotes_strings_that_look_like_dates() {
+        let value = PdxRelationValue::String("2200.1.1".into());
+        assert_eq!(value.to_string(), r#""2200.1.1""#);
+        assert_eq!(round_trip(r#"a = "2200.1.1""#), r#"a = "2200.1.1""#);
+        assert_eq!(parse_value(&format!("a = {}", value)), value);
+
+        // strings that only resemble dates are left bare.
+        assert_eq!(PdxRelationValue::String("2200.13.1".into()).to_string(), "2200.13.1");
+        assert_eq!(PdxRelationValue::String("1.5".into()).to_string(), "1.5");
+    }
+
+    #[test]
+    fn escapes_quoted_strings() {
+        let value = PdxRelationValue::String(r#"say "hi" \ \n"#.into());
+        assert_eq!(value.to_string(), r#""say \"hi\" \\ \\n""#);
+        assert_eq!(parse_value(&format!("a = {}", value)), value);
+        assert_eq!(round_trip(r#"a = "C:\\dir \"x\"""#), r#"a = "C:\\dir \"x\"""#);
+    }
+
+    #[test]
+    fn quotes_tags_and_empty_strings() {
+        assert_eq!(round_trip(r#""my tag" = { "" = x }"#), r#""my tag" = { "" = x }"#);
+        assert_eq!(round_trip(r#"a = "" b = { "" }"#), r#"a = "" b = { "" }"#);
+        assert_eq!(round_trip(r#""2200.1.1" = yes"#), "2200.1.1 = yes");
+    }
+}
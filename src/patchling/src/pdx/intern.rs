@@ -0,0 +1,97 @@
+use crate::pdx::{PdxBlock, PdxRelation, PdxRelationValue, VisitorMut, WalkControl};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+use twox_hash::RandomXxh3HashBuilder64;
+
+/// A shared pool of the strings used in PDX trees.
+///
+/// Game data repeats the same few thousand tags and values a great many times, so trees built
+/// with an interner share one allocation for every copy of a string. Comparing two interned
+/// strings that are equal only needs to compare their pointers.
+#[derive(Debug, Default)]
+pub struct PdxInterner {
+    strings: Mutex<HashSet<Arc<str>, RandomXxh3HashBuilder64>>,
+}
+impl PdxInterner {
+    /// Creates a new empty interner.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the shared copy of a string, adding it to the interner if needed.
+    pub fn intern(&self, str: &str) -> Arc<str> {
+        let mut strings = self.strings.lock().unwrap();
+        match strings.get(str) {
+            Some(interned) => interned.clone(),
+            None => {
+                let interned: Arc<str> = str.into();
+                strings.insert(interned.clone());
+                interned
+            }
+        }
+    }
+
+    /// Replaces a string with its shared copy. If the interner does not contain the string yet,
+    /// the given allocation becomes the shared copy.
+    pub fn intern_arc(&self, str: &mut Arc<str>) {
+        let mut strings = self.strings.lock().unwrap();
+        match strings.get(&**str) {
+            Some(interned) => *str = interned.clone(),
+            None => {
+                strings.insert(str.clone());
+            }
+        }
+    }
+
+    /// Replaces every string in a block with its shared copy. This is used for trees that were
+    /// not built by the parser, such as those read back from Lua.
+    pub fn intern_block(&self, block: &mut PdxBlock) {
+        block.walk_mut(&mut InternVisitor(self));
+    }
+
+    /// Replaces every string in a relation with its shared copy.
+    pub fn intern_relation(&self, rel: &mut PdxRelation) {
+        self.intern_arc(&mut rel.tag);
+        self.intern_value(&mut rel.value);
+        if let PdxRelationValue::Block(block) = &mut rel.value {
+            self.intern_block(block);
+        }
+    }
+
+    fn intern_value(&self, value: &mut PdxRelationValue) {
+        match value {
+            PdxRelationValue::String(str)
+            | PdxRelationValue::Variable(str)
+            | PdxRelationValue::VariableExpr(str) => self.intern_arc(str),
+            _ => {}
+        }
+    }
+
+    /// Returns the number of distinct strings in the interner.
+    pub fn len(&self) -> usize {
+        self.strings.lock().unwrap().len()
+    }
+
+    /// Returns whether the interner contains no strings.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+struct InternVisitor<'a>(&'a PdxInterner);
+impl<'a> VisitorMut for InternVisitor<'a> {
+    fn visit_relation(&mut self, _path: &[Arc<str>], rel: &mut PdxRelation) -> WalkControl {
+        self.0.intern_arc(&mut rel.tag);
+        WalkControl::Continue
+    }
+    fn visit_value(&mut self, _path: &[Arc<str>], value: &mut PdxRelationValue) -> WalkControl {
+        self.0.intern_value(value);
+        WalkControl::Continue
+    }
+    fn visit_string(&mut self, _path: &[Arc<str>], str: &mut Arc<str>) -> WalkControl {
+        self.0.intern_arc(str);
+        WalkControl::Continue
+    }
+}
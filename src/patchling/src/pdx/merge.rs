@@ -0,0 +1,393 @@
+use crate::pdx::{
+    diff::pair_members, PdxBlock, PdxBlockContent, PdxRelation, PdxRelationValue, PdxSpan,
+};
+use serde::*;
+use std::{collections::HashMap, fmt, sync::Arc};
+
+/// The result of a three-way merge.
+#[derive(Clone, Debug)]
+pub struct PdxMerge<T> {
+    pub merged: T,
+    /// Changes that could not be combined. The merged result uses the version from the newer
+    /// side for each of these.
+    pub conflicts: Vec<PdxMergeConflict>,
+}
+
+/// A conflict between the changes two sides of a three-way merge made to the same member.
+///
+/// A side that is `None` deleted the member, and a base that is `None` means both sides added it.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PdxMergeConflict {
+    /// The chain of tags leading to the block the conflict is in.
+    pub path: Vec<Arc<str>>,
+    pub base: Option<PdxBlockContent>,
+    pub ours: Option<PdxBlockContent>,
+    pub theirs: Option<PdxBlockContent>,
+}
+impl PdxMergeConflict {
+    /// Returns where the older side's version of the member was parsed from.
+    pub fn ours_span(&self) -> Option<&PdxSpan> {
+        content_span(self.ours.as_ref())
+    }
+
+    /// Returns where the newer side's version of the member was parsed from.
+    pub fn theirs_span(&self) -> Option<&PdxSpan> {
+        content_span(self.theirs.as_ref())
+    }
+}
+
+fn content_span(content: Option<&PdxBlockContent>) -> Option<&PdxSpan> {
+    match content {
+        Some(PdxBlockContent::Relation(rel)) => rel.span.as_ref(),
+        _ => None,
+    }
+}
+
+impl fmt::Display for PdxMergeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Side<'a>(Option<&'a PdxBlockContent>);
+        impl<'a> fmt::Display for Side<'a> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self.0 {
+                    Some(content) => {
+                        if let Some(span) = content_span(Some(content)) {
+                            write!(f, "{}: ", span)?;
+                        }
+                        fmt::Display::fmt(content, f)
+                    }
+                    None => f.write_str("(deleted)"),
+                }
+            }
+        }
+
+        if !self.path.is_empty() {
+            write!(f, "in {}, ", self.path.join("."))?;
+        }
+        write!(f, "`{}` conflicts with `{}`", Side(self.ours.as_ref()), Side(self.theirs.as_ref()))
+    }
+}
+
+impl PdxBlock {
+    /// Merges the changes two blocks made to a common base block.
+    ///
+    /// Members are matched up the same way as in [`PdxBlock::diff`]. Changes to different
+    /// members are combined, and changes to blocks under the same tag are merged recursively.
+    /// The order of members follows `ours`, with members added by `theirs` placed after the
+    /// member they follow in `theirs`. When the two sides conflict, `theirs` is used.
+    pub fn merge3(base: &PdxBlock, ours: &PdxBlock, theirs: &PdxBlock) -> PdxMerge<PdxBlock> {
+        let mut conflicts = Vec::new();
+        let merged = merge_blocks(&mut Vec::new(), base, ours, theirs, &mut conflicts);
+        PdxMerge { merged, conflicts }
+    }
+}
+
+impl PdxRelation {
+    /// Merges the changes two versions of a relation made to a common base version, which may
+    /// not exist if both sides added the relation independently.
+    ///
+    /// This works the same way as [`PdxBlock::merge3`].
+    pub fn merge3(
+        base: Option<&PdxRelation>,
+        ours: &PdxRelation,
+        theirs: &PdxRelation,
+    ) -> PdxMerge<PdxRelation> {
+        let mut conflicts = Vec::new();
+        let base = base.map(|x| PdxBlockContent::Relation(x.clone()));
+        let ours = PdxBlockContent::Relation(ours.clone());
+        let theirs = PdxBlockContent::Relation(theirs.clone());
+        let merged = merge_members(&mut Vec::new(), base.as_ref(), &ours, &theirs, &mut conflicts);
+        match merged {
+            PdxBlockContent::Relation(merged) => PdxMerge { merged, conflicts },
+            PdxBlockContent::String(_) => unreachable!(),
+        }
+    }
+}
+
+fn invert(pairing: &[Option<usize>], len: usize) -> Vec<Option<usize>> {
+    let mut inverted = vec![None; len];
+    for (i, j) in pairing.iter().enumerate() {
+        if let Some(j) = j {
+            inverted[*j] = Some(i);
+        }
+    }
+    inverted
+}
+
+fn conflict(
+    path: &[Arc<str>],
+    base: Option<&PdxBlockContent>,
+    ours: Option<&PdxBlockContent>,
+    theirs: Option<&PdxBlockContent>,
+) -> PdxMergeConflict {
+    PdxMergeConflict {
+        path: path.to_vec(),
+        base: base.cloned(),
+        ours: ours.cloned(),
+        theirs: theirs.cloned(),
+    }
+}
+
+fn merge_blocks(
+    path: &mut Vec<Arc<str>>,
+    base: &PdxBlock,
+    ours: &PdxBlock,
+    theirs: &PdxBlock,
+    conflicts: &mut Vec<PdxMergeConflict>,
+) -> PdxBlock {
+    let (base, ours, theirs) = (&base.contents, &ours.contents, &theirs.contents);
+    let base_to_ours = pair_members(base, ours);
+    let base_to_theirs = pair_members(base, theirs);
+    let ours_to_base = invert(&base_to_ours, ours.len());
+    let theirs_to_base = invert(&base_to_theirs, theirs.len());
+
+    // members both sides added are matched up with each other.
+    let ours_added: Vec<usize> = (0..ours.len()).filter(|&j| ours_to_base[j].is_none()).collect();
+    let theirs_added: Vec<usize> =
+        (0..theirs.len()).filter(|&k| theirs_to_base[k].is_none()).collect();
+    let added_pairing = pair_members(
+        &ours_added.iter().map(|&j| &ours[j]).collect::<Vec<_>>(),
+        &theirs_added.iter().map(|&k| &theirs[k]).collect::<Vec<_>>(),
+    );
+    let mut ours_to_theirs_added = vec![None; ours.len()];
+    let mut theirs_to_ours_added = vec![None; theirs.len()];
+    for (i, partner) in added_pairing.iter().enumerate() {
+        if let Some(partner) = partner {
+            ours_to_theirs_added[ours_added[i]] = Some(theirs_added[*partner]);
+            theirs_to_ours_added[theirs_added[*partner]] = Some(ours_added[i]);
+        }
+    }
+
+    // find the members only in `theirs` that must be added, and the member of `ours` they follow.
+    let mut inserted_after: HashMap<Option<usize>, Vec<PdxBlockContent>> = HashMap::new();
+    let mut anchor = None;
+    for (k, content) in theirs.iter().enumerate() {
+        match theirs_to_base[k] {
+            Some(i) => match base_to_ours[i] {
+                Some(j) => anchor = Some(j),
+                None => {
+                    if *content != base[i] {
+                        // deleted by ours, but changed by theirs.
+                        conflicts.push(conflict(path, Some(&base[i]), None, Some(content)));
+                        inserted_after.entry(anchor).or_default().push(content.clone());
+                    }
+                }
+            },
+            None => match theirs_to_ours_added[k] {
+                Some(j) => anchor = Some(j),
+                None => inserted_after.entry(anchor).or_default().push(content.clone()),
+            },
+        }
+    }
+
+    let mut merged = Vec::new();
+    merged.extend(inserted_after.remove(&None).unwrap_or_default());
+    for (j, content) in ours.iter().enumerate() {
+        match ours_to_base[j] {
+            Some(i) => match base_to_theirs[i] {
+                Some(k) => {
+                    merged.push(merge_members(path, Some(&base[i]), content, &theirs[k], conflicts))
+                }
+                None => {
+                    if *content != base[i] {
+                        // changed by ours, but deleted by theirs.
+                        conflicts.push(conflict(path, Some(&base[i]), Some(content), None));
+                    }
+                }
+            },
+            None => match ours_to_theirs_added[j] {
+                Some(k) => merged.push(merge_members(path, None, content, &theirs[k], conflicts)),
+                None => merged.push(content.clone()),
+            },
+        }
+        merged.extend(inserted_after.remove(&Some(j)).unwrap_or_default());
+    }
+    PdxBlock::new(merged)
+}
+
+fn merge_members(
+    path: &mut Vec<Arc<str>>,
+    base: Option<&PdxBlockContent>,
+    ours: &PdxBlockContent,
+    theirs: &PdxBlockContent,
+    conflicts: &mut Vec<PdxMergeConflict>,
+) -> PdxBlockContent {
+    if ours == theirs || base == Some(ours) {
+        return theirs.clone();
+    }
+    if base == Some(theirs) {
+        return ours.clone();
+    }
+
+    // members are only paired if they are identical, or relations with the same tag.
+    if let (PdxBlockContent::Relation(ours_rel), PdxBlockContent::Relation(theirs_rel)) =
+        (ours, theirs)
+    {
+        if let (PdxRelationValue::Block(ours_block), PdxRelationValue::Block(theirs_block)) =
+            (&ours_rel.value, &theirs_rel.value)
+        {
+            if ours_rel.relation == theirs_rel.relation {
+                let empty = PdxBlock::new(Vec::new());
+                let base_block = match base {
+                    Some(PdxBlockContent::Relation(PdxRelation {
+                        relation,
+                        value: PdxRelationValue::Block(block),
+                        ..
+                    })) if *relation == ours_rel.relation => block,
+                    _ => &empty,
+                };
+
+                path.push(theirs_rel.tag.clone());
+                let merged = merge_blocks(path, base_block, ours_block, theirs_block, conflicts);
+                path.pop();
+                return PdxBlockContent::Relation(PdxRelation {
+                    tag: theirs_rel.tag.clone(),
+                    relation: theirs_rel.relation,
+                    value: PdxRelationValue::Block(merged),
+                    span: theirs_rel.span.clone(),
+                });
+            }
+        }
+    }
+
+    conflicts.push(conflict(path, base, Some(ours), Some(theirs)));
+    theirs.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(file_name: &str, src: &str) -> PdxBlock {
+        PdxBlock::parse_file(file_name, src.as_bytes()).unwrap()
+    }
+
+    fn merge_src(base: &str, ours: &str, theirs: &str) -> PdxMerge<PdxBlock> {
+        let base = parse("base.txt", base);
+        let ours = parse("ours.txt", ours);
+        let theirs = parse("theirs.txt", theirs);
+        PdxBlock::merge3(&base, &ours, &theirs)
+    }
+
+    fn assert_merged(merge: &PdxMerge<PdxBlock>, expected: &str) {
+        assert_eq!(merge.merged.contents, parse("expected.txt", expected).contents);
+    }
+
+    #[test]
+    fn combines_changes_to_different_members() {
+        let merge = merge_src(
+            "cost = { energy = 100 } upkeep = { energy = 1 }",
+            "cost = { energy = 200 } upkeep = { energy = 1 }",
+            "cost = { energy = 100 } potential = { always = yes } upkeep = { energy = 1 }",
+        );
+        assert!(merge.conflicts.is_empty(), "{:?}", merge.conflicts);
+        assert_merged(
+            &merge,
+            "cost = { energy = 200 } potential = { always = yes } upkeep = { energy = 1 }",
+        );
+    }
+
+    #[test]
+    fn combines_changes_inside_blocks() {
+        let merge = merge_src(
+            "cost = { energy = 100 minerals = 50 }",
+            "cost = { energy = 200 minerals = 50 }",
+            "cost = { energy = 100 minerals = 75 }",
+        );
+        assert!(merge.conflicts.is_empty(), "{:?}", merge.conflicts);
+        assert_merged(&merge, "cost = { energy = 200 minerals = 75 }");
+    }
+
+    #[test]
+    fn reports_conflicting_changes() {
+        let merge = merge_src("cost = 100", "\ncost = 200", "cost = 300");
+        assert_merged(&merge, "cost = 300");
+        assert_eq!(merge.conflicts.len(), 1);
+
+        let conflict = &merge.conflicts[0];
+        assert!(conflict.path.is_empty());
+        assert!(conflict.base.is_some());
+        let ours_span = conflict.ours_span().unwrap();
+        assert_eq!(&*ours_span.file, "ours.txt");
+        assert_eq!(ours_span.line, 2);
+        let theirs_span = conflict.theirs_span().unwrap();
+        assert_eq!(&*theirs_span.file, "theirs.txt");
+        assert_eq!(theirs_span.line, 1);
+    }
+
+    #[test]
+    fn reports_conflict_paths() {
+        let merge = merge_src(
+            "cost = { energy = 100 }",
+            "cost = { energy = 200 }",
+            "cost = { energy = 300 }",
+        );
+        assert_merged(&merge, "cost = { energy = 300 }");
+        assert_eq!(merge.conflicts.len(), 1);
+        assert_eq!(merge.conflicts[0].path, vec![Arc::from("cost")]);
+    }
+
+    #[test]
+    fn reports_delete_vs_modify() {
+        // ours deleted the member, and theirs changed it.
+        let merge = merge_src("a = 1 b = 2", "a = 1", "a = 1 b = 3");
+        assert_merged(&merge, "a = 1 b = 3");
+        assert_eq!(merge.conflicts.len(), 1);
+        assert!(merge.conflicts[0].ours.is_none());
+        assert!(merge.conflicts[0].theirs.is_some());
+
+        // ours changed the member, and theirs deleted it.
+        let merge = merge_src("a = 1 b = 2", "a = 1 b = 3", "a = 1");
+        assert_merged(&merge, "a = 1");
+        assert_eq!(merge.conflicts.len(), 1);
+        assert!(merge.conflicts[0].ours.is_some());
+        assert!(merge.conflicts[0].theirs.is_none());
+
+        // deleting a member neither side changed is not a conflict.
+        let merge = merge_src("a = 1 b = 2", "a = 1", "a = 1 b = 2");
+        assert!(merge.conflicts.is_empty(), "{:?}", merge.conflicts);
+        assert_merged(&merge, "a = 1");
+    }
+
+    #[test]
+    fn merges_repeated_keys() {
+        let merge = merge_src(
+            "modifier = { a = 1 } modifier = { b = 1 }",
+            "modifier = { a = 1 } modifier = { b = 2 }",
+            "modifier = { a = 1 } modifier = { b = 1 } modifier = { c = 1 }",
+        );
+        assert!(merge.conflicts.is_empty(), "{:?}", merge.conflicts);
+        assert_merged(&merge, "modifier = { a = 1 } modifier = { b = 2 } modifier = { c = 1 }");
+    }
+
+    #[test]
+    fn anchors_members_added_by_theirs() {
+        let merge = merge_src("a = 1 c = 3", "x = 0 a = 1 c = 3", "a = 1 b = 2 c = 3 d = 4");
+        assert!(merge.conflicts.is_empty(), "{:?}", merge.conflicts);
+        assert_merged(&merge, "x = 0 a = 1 b = 2 c = 3 d = 4");
+    }
+
+    #[test]
+    fn pairs_members_both_sides_added() {
+        let merge = merge_src("a = 1", "a = 1 b = 2", "a = 1 b = 2");
+        assert!(merge.conflicts.is_empty(), "{:?}", merge.conflicts);
+        assert_merged(&merge, "a = 1 b = 2");
+
+        let merge = merge_src("a = 1", "a = 1 b = { x = 1 }", "a = 1 b = { y = 1 }");
+        assert!(merge.conflicts.is_empty(), "{:?}", merge.conflicts);
+        assert_merged(&merge, "a = 1 b = { y = 1 x = 1 }");
+    }
+
+    #[test]
+    fn merges_relations_without_a_base() {
+        let ours = parse("ours.txt", "rule = { x = 1 }");
+        let theirs = parse("theirs.txt", "rule = { x = 2 }");
+        let relation = |block: &PdxBlock| match &block.contents[0] {
+            PdxBlockContent::Relation(rel) => rel.clone(),
+            _ => unreachable!(),
+        };
+        let merge = PdxRelation::merge3(None, &relation(&ours), &relation(&theirs));
+        assert_eq!(merge.merged, relation(&theirs));
+        assert_eq!(merge.conflicts.len(), 1);
+        assert!(merge.conflicts[0].base.is_none());
+    }
+}
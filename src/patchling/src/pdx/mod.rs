@@ -1,8 +1,23 @@
-// TODO: Add an interner for our Arc<str>s.
-
+mod cst;
+mod diff;
+mod encoding;
 mod export;
+mod intern;
+mod merge;
 mod model;
 mod parser;
+mod query;
+pub mod serde_pdx;
+mod variables;
 mod walk;
 
+pub use cst::*;
+pub use diff::{PdxDiff, PdxDiffChange, PdxDiffEntry};
+pub use encoding::PdxEncoding;
+pub use intern::PdxInterner;
+pub use merge::{PdxMerge, PdxMergeConflict};
 pub use model::*;
+pub use parser::PdxDiagnostic;
+pub use query::{PdxQuery, PdxQueryMatch};
+pub use variables::PdxVariables;
+pub use walk::{Visitor, VisitorMut, WalkControl};
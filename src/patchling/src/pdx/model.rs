@@ -1,3 +1,4 @@
+use crate::pdx::PdxEncoding;
 use serde::{
     de::{
         value::{MapAccessDeserializer, SeqAccessDeserializer},
@@ -7,6 +8,19 @@ use serde::{
 };
 use std::{fmt, fmt::Formatter, marker::PhantomData, sync::Arc};
 
+/// The location of a node in a PDX source file.
+#[derive(Serialize, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct PdxSpan {
+    pub file: Arc<str>,
+    pub line: u32,
+    pub column: u32,
+}
+impl fmt::Display for PdxSpan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
 #[serde(rename_all = "snake_case")]
@@ -25,6 +39,19 @@ impl Default for PdxRelationType {
     }
 }
 
+/// The color space of a color literal.
+#[derive(Serialize, Deserialize)]
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum PdxColorSpace {
+    /// `rgb { r g b }` or `rgb { r g b a }`, with components from 0 to 255.
+    Rgb,
+    /// `hsv { h s v }` or `hsv { h s v a }`, with components from 0 to 1.
+    Hsv,
+    /// `hsv360 { h s v }`, with the hue from 0 to 360 and other components from 0 to 100.
+    Hsv360,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 
 pub enum PdxRelationValue {
@@ -38,15 +65,29 @@ pub enum PdxRelationValue {
     Variable(Arc<str>),
     #[serde(rename = "var_expr")]
     VariableExpr(Arc<str>),
+    #[serde(rename = "color")]
+    Color { space: PdxColorSpace, components: Vec<f64> },
+    #[serde(rename = "date")]
+    Date { y: i32, m: u8, d: u8 },
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PdxRelation {
     pub tag: Arc<str>,
     #[serde(skip_serializing_if = "is_relation_normal", default)]
     pub relation: PdxRelationType,
     #[serde(flatten)]
     pub value: PdxRelationValue,
+    /// Where this relation was parsed from. This is not mirrored into Lua.
+    #[serde(skip)]
+    pub span: Option<PdxSpan>,
+}
+impl PartialEq for PdxRelation {
+    fn eq(&self, other: &Self) -> bool {
+        // spans are deliberately ignored, so identical definitions from different files compare
+        // as equal.
+        self.tag == other.tag && self.relation == other.relation && self.value == other.value
+    }
 }
 fn is_relation_normal(relation: &PdxRelationType) -> bool {
     *relation == PdxRelationType::Normal
@@ -87,8 +128,27 @@ impl<'de> Visitor<'de> for PdxBlockVisitor {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(transparent)]
 pub struct PdxBlock {
     pub contents: Vec<PdxBlockContent>,
+    /// Where the opening bracket of this block was parsed from. Bare strings in a block do not
+    /// carry their own spans, and are located using this instead.
+    #[serde(skip)]
+    pub span: Option<PdxSpan>,
+    /// The encoding of the file this block was parsed from. This is only set for the root block
+    /// of a parsed file.
+    #[serde(skip)]
+    pub encoding: Option<PdxEncoding>,
+}
+impl PdxBlock {
+    /// Creates a new block with no source location.
+    pub fn new(contents: Vec<PdxBlockContent>) -> Self {
+        PdxBlock { contents, span: None, encoding: None }
+    }
+}
+impl PartialEq for PdxBlock {
+    fn eq(&self, other: &Self) -> bool {
+        self.contents == other.contents
+    }
 }
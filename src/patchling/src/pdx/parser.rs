@@ -1,26 +1,170 @@
-use crate::pdx::{PdxBlock, PdxBlockContent, PdxRelation, PdxRelationType, PdxRelationValue};
+use crate::pdx::{
+    PdxBlock, PdxBlockContent, PdxColorSpace, PdxCstBlock, PdxCstNode, PdxEncoding, PdxInterner,
+    PdxRelation, PdxRelationType, PdxRelationValue, PdxSourceFile, PdxSpan,
+};
 use anyhow::*;
-use std::{str::FromStr, sync::Arc};
+use std::{fmt, ops::Range, str::FromStr, sync::Arc};
+
+/// A syntax error found while parsing a PDX file.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct PdxDiagnostic {
+    pub span: PdxSpan,
+    pub message: String,
+}
+impl fmt::Display for PdxDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+impl std::error::Error for PdxDiagnostic {}
 
 struct ParserCtx<'a> {
     source: &'a [u8],
     source_str: &'a str,
     cursor: usize,
 
-    file_name: &'a str,
+    file_name: Arc<str>,
+    interner: Option<&'a PdxInterner>,
     cur_line: usize,
     cur_col: usize,
+
+    recover: bool,
+    diagnostics: Vec<PdxDiagnostic>,
+
+    cst_stack: Option<Vec<Vec<PdxCstNode>>>,
+    cst_block: Option<PdxCstBlock>,
 }
 impl<'a> ParserCtx<'a> {
-    fn new(file_name: &'a str, src: &'a str) -> Self {
+    fn new(
+        file_name: &str,
+        src: &'a str,
+        recover: bool,
+        interner: Option<&'a PdxInterner>,
+    ) -> Self {
         ParserCtx {
             source: src.as_bytes(),
             source_str: src,
             cursor: 0,
-            file_name,
+            file_name: match interner {
+                Some(interner) => interner.intern(file_name),
+                None => file_name.into(),
+            },
+            interner,
             cur_line: 1,
             cur_col: 1,
+            recover,
+            diagnostics: Vec::new(),
+            cst_stack: None,
+            cst_block: None,
+        }
+    }
+
+    /// Creates a string for the parsed tree, sharing it with other trees if we have an interner.
+    fn intern(&self, str: &str) -> Arc<str> {
+        match self.interner {
+            Some(interner) => interner.intern(str),
+            None => str.into(),
+        }
+    }
+
+    /// Enables building a syntax tree alongside the parsed model.
+    fn with_cst(mut self) -> Self {
+        self.cst_stack = Some(Vec::new());
+        self
+    }
+
+    /// Starts collecting the syntax tree of a new block.
+    fn cst_enter(&mut self) {
+        if let Some(stack) = &mut self.cst_stack {
+            stack.push(Vec::new());
+        }
+    }
+
+    /// Finishes the syntax tree of the current block, which started at the given position.
+    fn cst_exit(&mut self, start: usize) {
+        if let Some(stack) = &mut self.cst_stack {
+            let members = stack.pop().expect("unbalanced syntax tree");
+            self.cst_block = Some(PdxCstBlock { range: start..self.cursor, members });
+        }
+    }
+
+    /// Adds a block member to the syntax tree.
+    fn cst_push(&mut self, range: Range<usize>) {
+        let block = self.cst_block.take();
+        if let Some(stack) = &mut self.cst_stack {
+            let members = stack.last_mut().expect("unbalanced syntax tree");
+            members.push(PdxCstNode { range, block });
+        }
+    }
+
+    /// Returns the current location of the cursor.
+    fn span(&self) -> PdxSpan {
+        PdxSpan {
+            file: self.file_name.clone(),
+            line: self.cur_line as u32,
+            column: self.cur_col as u32,
+        }
+    }
+
+    /// Creates a diagnostic at the current location of the cursor.
+    fn diagnostic(&self, message: &str) -> PdxDiagnostic {
+        PdxDiagnostic { span: self.span(), message: message.to_string() }
+    }
+
+    /// Reports a diagnostic, or returns it as an error if we are not recovering from errors.
+    fn report(&mut self, diagnostic: PdxDiagnostic) -> Result<()> {
+        if self.recover {
+            self.diagnostics.push(diagnostic);
+            Ok(())
+        } else {
+            Err(diagnostic.into())
+        }
+    }
+
+    /// Recovers from an error returned while parsing a block member, if possible.
+    fn recover_from(&mut self, error: Error) -> Result<()> {
+        if !self.recover {
+            return Err(error);
+        }
+        let diagnostic = error.downcast::<PdxDiagnostic>()?;
+        self.diagnostics.push(diagnostic);
+        self.cst_block = None;
+        self.skip_to_next_relation()
+    }
+
+    /// Skips past the rest of a malformed block member, including any brackets it contains.
+    fn skip_to_next_relation(&mut self) -> Result<()> {
+        let start = self.cursor;
+        let mut depth = 0usize;
+        while self.cursor < self.source.len() {
+            match self.source[self.cursor] {
+                b' ' | b'\t' | b'\r' | b'\n' | b'#' if depth == 0 => break,
+                b'}' if depth == 0 => break,
+                b'{' => depth += 1,
+                b'}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.advance_cur(1)?;
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            self.advance_cur(1)?;
+        }
+        if self.cursor == start && self.cursor < self.source.len() && !self.peek_tok(b"}")? {
+            // always make progress, even on a single stray character.
+            self.advance_cur(1)?;
         }
+        Ok(())
+    }
+
+    /// Returns the number of bytes until the end of the current line.
+    fn remaining_line_len(&self) -> usize {
+        self.source[self.cursor..]
+            .iter()
+            .position(|x| *x == b'\n')
+            .unwrap_or(self.source.len() - self.cursor)
     }
 
     /// Advances the cursor by a given amount.
@@ -28,10 +172,7 @@ impl<'a> ParserCtx<'a> {
         assert_ne!(count, 0);
         ensure!(
             self.cursor + count <= self.source.len(),
-            "{}:{}:{}: Unexpected end of PDX source file.",
-            self.file_name,
-            self.cur_line,
-            self.cur_col,
+            self.diagnostic("Unexpected end of PDX source file."),
         );
         for _ in 0..count {
             match self.source[self.cursor] {
@@ -57,9 +198,10 @@ impl<'a> ParserCtx<'a> {
                 b' ' | b'\t' | b'\r' | b'\n' => {}
                 // skip comments
                 b'#' => {
-                    while self.source[self.cursor] != b'\n' {
+                    while self.cursor < self.source.len() && self.source[self.cursor] != b'\n' {
                         self.advance_cur(1)?;
                     }
+                    continue;
                 }
                 // we're done!
                 _ => break,
@@ -109,37 +251,45 @@ impl<'a> ParserCtx<'a> {
                 count += 1;
             }
 
-            ensure!(
-                self.cursor + count <= self.source.len(),
-                "{}:{}:{}: Found unterminated string.",
-                self.file_name,
-                self.cur_line,
-                self.cur_col,
-            );
+            let terminated = self.cursor + count < self.source.len();
+            if !terminated {
+                // the game treats an unterminated string as ending at the end of the line.
+                self.report(self.diagnostic("Found unterminated string."))?;
+                count = self.remaining_line_len();
+            }
 
             // parses the actual string itself.
             let tok = &self.source_str[self.cursor..self.cursor + count];
-            self.advance_cur(count + 1)?;
+            let advance = if terminated { count + 1 } else { count };
+            if advance != 0 {
+                self.advance_cur(advance)?;
+            }
             if contains_escapes {
                 let mut owned = String::new();
                 let mut has_escape = false;
                 for ch in tok.chars() {
                     if has_escape {
+                        // only quotes and backslashes are escaped; any other character keeps its
+                        // backslash, such as the `\n` used in localisation.
                         match ch {
                             '\"' | '\\' => {}
-                            _ => owned.push('\"'),
+                            _ => owned.push('\\'),
                         }
                         owned.push(ch);
-                    } else if ch == '\\' {
                         has_escape = false;
+                    } else if ch == '\\' {
+                        has_escape = true;
                     } else {
                         owned.push(ch);
                     }
                 }
-                assert!(!has_escape);
-                Ok(Some(owned.into()))
+                if has_escape {
+                    // a backslash at the end of an unterminated string.
+                    owned.push('\\');
+                }
+                Ok(Some(self.intern(&owned)))
             } else {
-                Ok(Some(tok.into()))
+                Ok(Some(self.intern(tok)))
             }
         }
     }
@@ -149,7 +299,7 @@ impl<'a> ParserCtx<'a> {
         self.skip_whitespace()?;
 
         if self.check_tok(b"@")? {
-            if self.check_tok(b"\\[")? {
+            if self.check_tok(b"\\[")? || self.check_tok(b"[")? {
                 let mut count = 0;
                 while self.cursor + count < self.source.len() {
                     match self.source[self.cursor + count] {
@@ -159,9 +309,18 @@ impl<'a> ParserCtx<'a> {
                     count += 1;
                 }
 
+                let terminated = self.cursor + count < self.source.len();
+                if !terminated {
+                    self.report(self.diagnostic("Found unterminated variable expression."))?;
+                    count = self.remaining_line_len();
+                }
+
                 let res = &self.source_str[self.cursor..self.cursor + count];
-                self.advance_cur(count + 1)?;
-                Ok(Some(PdxRelationValue::VariableExpr(res.into())))
+                let advance = if terminated { count + 1 } else { count };
+                if advance != 0 {
+                    self.advance_cur(advance)?;
+                }
+                Ok(Some(PdxRelationValue::VariableExpr(self.intern(res))))
             } else {
                 Ok(Some(PdxRelationValue::Variable(self.parse_value_id()?)))
             }
@@ -189,17 +348,11 @@ impl<'a> ParserCtx<'a> {
                 count += 1;
             }
 
-            ensure!(
-                count != 0,
-                "{}:{}:{}: Could not parse identifier.",
-                self.file_name,
-                self.cur_line,
-                self.cur_col
-            );
+            ensure!(count != 0, self.diagnostic("Could not parse identifier."));
 
             let res = &self.source_str[self.cursor..self.cursor + count];
             self.advance_cur(count)?;
-            Ok(res.into())
+            Ok(self.intern(res))
         }
     }
 
@@ -211,7 +364,7 @@ impl<'a> ParserCtx<'a> {
         if let Some(str) = self.parse_quoted_str()? {
             Ok(str)
         } else {
-            let mut count = 0;
+            let mut count = self.source.len() - self.cursor;
             for (idx, ch) in self.source_str[self.cursor..].char_indices() {
                 match ch {
                     'a'..='z' | 'A'..='Z' | '0'..='9' => {}
@@ -225,20 +378,47 @@ impl<'a> ParserCtx<'a> {
                 }
             }
 
-            ensure!(
-                count != 0,
-                "{}:{}:{}: Could not parse identifier.",
-                self.file_name,
-                self.cur_line,
-                self.cur_col
-            );
+            ensure!(count != 0, self.diagnostic("Could not parse identifier."));
 
             let res = &self.source_str[self.cursor..self.cursor + count];
             self.advance_cur(count)?;
-            Ok(res.into())
+            Ok(self.intern(res))
         }
     }
 
+    /// Parses the bracketed components of a color literal.
+    fn parse_color_components(&mut self) -> Result<Vec<f64>> {
+        let span = self.span();
+        ensure!(self.check_tok(b"{")?, self.diagnostic("Expected an opening bracket."));
+
+        let mut components = Vec::new();
+        loop {
+            if self.check_end()? {
+                self.report(PdxDiagnostic {
+                    span,
+                    message: "Bracket is never closed.".to_string(),
+                })?;
+                break;
+            } else if self.check_tok(b"}")? {
+                break;
+            }
+
+            let diagnostic = self.diagnostic("Could not parse color component.");
+            match self.parse_value_id() {
+                Ok(component) => match f64::from_str(&component) {
+                    Ok(component) => components.push(component),
+                    Err(_) => self.report(diagnostic)?,
+                },
+                Err(_) => {
+                    // skip a single character so we can continue to the closing bracket.
+                    self.report(diagnostic)?;
+                    self.advance_cur(1)?;
+                }
+            }
+        }
+        Ok(components)
+    }
+
     fn check_end(&mut self) -> Result<bool> {
         self.skip_whitespace()?;
         Ok(self.cursor == self.source.len())
@@ -269,59 +449,329 @@ impl PdxRelationType {
     }
 }
 
+impl PdxColorSpace {
+    fn parse(keyword: &str) -> Option<Self> {
+        match keyword {
+            "rgb" => Some(PdxColorSpace::Rgb),
+            "hsv" => Some(PdxColorSpace::Hsv),
+            "hsv360" => Some(PdxColorSpace::Hsv360),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a date of the form `y.m.d`.
+pub(crate) fn parse_date(value: &str) -> Option<PdxRelationValue> {
+    let mut split = value.split('.');
+    let y = i32::from_str(split.next()?).ok()?;
+    let m = u8::from_str(split.next()?).ok()?;
+    let d = u8::from_str(split.next()?).ok()?;
+    if split.next().is_some() || !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+    Some(PdxRelationValue::Date { y, m, d })
+}
+
 impl PdxBlockContent {
     fn parse(ctx: &mut ParserCtx<'_>) -> Result<Self> {
+        ctx.skip_whitespace()?;
+        let start = ctx.cursor;
+        let (content, end) = PdxBlockContent::parse_inner(ctx)?;
+        ctx.cst_push(start..end);
+        Ok(content)
+    }
+
+    /// Parses a block member, returning it alongside the position it ends at.
+    fn parse_inner(ctx: &mut ParserCtx<'_>) -> Result<(Self, usize)> {
+        let span = ctx.span();
         let key = ctx.parse_key_id()?;
+        let key_end = ctx.cursor;
         if let Some(relation) = PdxRelationType::parse(ctx)? {
             ctx.skip_whitespace()?;
+            let mut value_end = None;
             let value = if ctx.peek_tok(b"{")? {
                 PdxRelationValue::Block(PdxBlock::parse_bracketed(ctx)?)
             } else if let Some(var) = ctx.parse_variable()? {
                 var
             } else {
+                let quoted = ctx.peek_tok(b"\"")?;
                 let raw_value = ctx.parse_value_id()?;
                 if let Ok(float_value) = f64::from_str(&raw_value) {
                     PdxRelationValue::Numeric(float_value)
+                } else if let (false, Some(space)) = (quoted, PdxColorSpace::parse(&raw_value)) {
+                    value_end = Some(ctx.cursor);
+                    ctx.skip_whitespace()?;
+                    if ctx.peek_tok(b"{")? {
+                        value_end = None;
+                        let components = ctx.parse_color_components()?;
+                        PdxRelationValue::Color { space, components }
+                    } else {
+                        PdxRelationValue::String(raw_value)
+                    }
+                } else if let (false, Some(date)) = (quoted, parse_date(&raw_value)) {
+                    date
                 } else {
                     PdxRelationValue::String(raw_value)
                 }
             };
-            Ok(PdxBlockContent::Relation(PdxRelation { tag: key, value, relation }))
+            let content = PdxBlockContent::Relation(PdxRelation {
+                tag: key,
+                relation,
+                value,
+                span: Some(span),
+            });
+            Ok((content, value_end.unwrap_or(ctx.cursor)))
         } else {
-            Ok(PdxBlockContent::String(key))
+            Ok((PdxBlockContent::String(key), key_end))
         }
     }
 }
 
 impl PdxBlock {
     fn parse_bracketed(ctx: &mut ParserCtx<'_>) -> Result<Self> {
+        let span = ctx.span();
+        let start = ctx.cursor;
+        ensure!(ctx.check_tok(b"{")?, ctx.diagnostic("Expected an opening bracket."));
+        ctx.cst_enter();
+
         let mut contents = Vec::new();
-        if ctx.check_tok(b"{")? {
-            loop {
-                ctx.skip_whitespace()?;
-                if ctx.check_tok(b"}")? {
-                    break;
-                } else {
-                    contents.push(PdxBlockContent::parse(ctx)?);
+        loop {
+            if ctx.check_end()? {
+                ctx.report(PdxDiagnostic {
+                    span: span.clone(),
+                    message: "Bracket is never closed.".to_string(),
+                })?;
+                break;
+            } else if ctx.check_tok(b"}")? {
+                break;
+            } else {
+                match PdxBlockContent::parse(ctx) {
+                    Ok(content) => contents.push(content),
+                    Err(e) => ctx.recover_from(e)?,
                 }
             }
-        } else {
-            panic!("no opening bracket?");
         }
-        Ok(PdxBlock { contents })
+        ctx.cst_exit(start);
+        Ok(PdxBlock { contents, span: Some(span), encoding: None })
     }
 
-    pub fn parse_file(file_name: &str, file_data: &[u8]) -> Result<Self> {
-        let mut ctx = ParserCtx::new(file_name, std::str::from_utf8(file_data)?);
-        ctx.check_tok(b"\xEF\xBB\xBF")?; // remove UTF-8 BOM if one exists.
+    fn parse_root(ctx: &mut ParserCtx<'_>) -> Result<Self> {
+        let span = ctx.span();
+        let start = ctx.cursor;
+        ctx.cst_enter();
 
         let mut contents = Vec::new();
         loop {
             if ctx.check_end()? {
                 break;
+            } else if ctx.peek_tok(b"}")? {
+                ctx.report(ctx.diagnostic("Found unmatched closing bracket."))?;
+                ctx.advance_cur(1)?;
+            } else {
+                match PdxBlockContent::parse(ctx) {
+                    Ok(content) => contents.push(content),
+                    Err(e) => ctx.recover_from(e)?,
+                }
             }
-            contents.push(PdxBlockContent::parse(&mut ctx)?);
         }
-        Ok(PdxBlock { contents })
+        ctx.cst_exit(start);
+        Ok(PdxBlock { contents, span: Some(span), encoding: None })
+    }
+
+    fn parse_ctx(
+        file_name: &str,
+        file_data: &[u8],
+        recover: bool,
+        interner: Option<&PdxInterner>,
+    ) -> Result<(Self, Vec<PdxDiagnostic>)> {
+        let (source, encoding) = PdxEncoding::decode(file_data);
+        let mut ctx = ParserCtx::new(file_name, &source, recover, interner);
+        let mut block = PdxBlock::parse_root(&mut ctx)?;
+        block.encoding = Some(encoding);
+        Ok((block, ctx.diagnostics))
+    }
+
+    /// Parses a PDX file, failing on the first syntax error found.
+    pub fn parse_file(file_name: &str, file_data: &[u8]) -> Result<Self> {
+        Ok(PdxBlock::parse_ctx(file_name, file_data, false, None)?.0)
+    }
+
+    /// Parses a PDX file, failing on the first syntax error found. Every string in the parsed
+    /// tree is shared through the given interner.
+    pub fn parse_file_interned(
+        file_name: &str,
+        file_data: &[u8],
+        interner: &PdxInterner,
+    ) -> Result<Self> {
+        Ok(PdxBlock::parse_ctx(file_name, file_data, false, Some(interner))?.0)
+    }
+
+    /// Parses a PDX file, skipping past syntax errors in the same way the game does where
+    /// possible. Returns the partially parsed file, and every syntax error found in it.
+    pub fn parse_file_recovering(
+        file_name: &str,
+        file_data: &[u8],
+    ) -> Result<(Self, Vec<PdxDiagnostic>)> {
+        PdxBlock::parse_ctx(file_name, file_data, true, None)
+    }
+
+    /// Parses a PDX file, recovering from syntax errors where possible. Every string in the
+    /// parsed tree is shared through the given interner.
+    pub fn parse_file_recovering_interned(
+        file_name: &str,
+        file_data: &[u8],
+        interner: &PdxInterner,
+    ) -> Result<(Self, Vec<PdxDiagnostic>)> {
+        PdxBlock::parse_ctx(file_name, file_data, true, Some(interner))
+    }
+}
+
+impl PdxSourceFile {
+    fn parse_ctx(
+        name: &str,
+        file_data: &[u8],
+        recover: bool,
+    ) -> Result<(Self, Vec<PdxDiagnostic>)> {
+        let (source, encoding) = PdxEncoding::decode(file_data);
+        let source: Arc<str> = source.into();
+        let mut ctx = ParserCtx::new(name, &source, recover, None).with_cst();
+        let mut contents = PdxBlock::parse_root(&mut ctx)?;
+        contents.encoding = Some(encoding);
+        let cst = ctx.cst_block.take().expect("syntax tree was not built");
+        let diagnostics = ctx.diagnostics;
+        let file = PdxSourceFile { name: name.into(), source, encoding, contents, cst };
+        Ok((file, diagnostics))
+    }
+
+    /// Parses a PDX file, retaining its comments and formatting.
+    pub fn parse(file_name: &str, file_data: &[u8]) -> Result<Self> {
+        Ok(PdxSourceFile::parse_ctx(file_name, file_data, false)?.0)
+    }
+
+    /// Parses a PDX file, retaining its comments and formatting, and recovering from syntax
+    /// errors where possible. Any text that could not be parsed is retained as trivia.
+    pub fn parse_recovering(
+        file_name: &str,
+        file_data: &[u8],
+    ) -> Result<(Self, Vec<PdxDiagnostic>)> {
+        PdxSourceFile::parse_ctx(file_name, file_data, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_recovering(src: &str) -> (String, Vec<String>) {
+        let (block, diagnostics) =
+            PdxBlock::parse_file_recovering("test.txt", src.as_bytes()).unwrap();
+        let block = block.display_file(false, false).to_string().trim_end().to_string();
+        (block, diagnostics.iter().map(|x| x.to_string()).collect())
+    }
+
+    #[test]
+    fn keeps_the_encoding_of_parsed_files() {
+        let encoding = |data: &[u8]| PdxBlock::parse_file("test.txt", data).unwrap().encoding;
+        assert_eq!(encoding(b"a = b"), Some(PdxEncoding::Utf8));
+        assert_eq!(encoding(b"\xEF\xBB\xBFa = b"), Some(PdxEncoding::Utf8Bom));
+        assert_eq!(encoding(b"a = \"Caf\xE9\""), Some(PdxEncoding::Windows1252));
+        assert_eq!(encoding(b"\xEF\xBB\xBFa = \"Caf\xE9\""), Some(PdxEncoding::Windows1252Bom));
+
+        // nested blocks do not carry an encoding of their own.
+        let block = PdxBlock::parse_file("test.txt", b"a = { b = c }").unwrap();
+        match &block.contents[0] {
+            PdxBlockContent::Relation(PdxRelation {
+                value: PdxRelationValue::Block(inner),
+                ..
+            }) => {
+                assert_eq!(inner.encoding, None)
+            }
+            content => panic!("expected a block, found {:?}", content),
+        }
+    }
+
+    #[test]
+    fn parses_identifiers_at_end_of_file() {
+        let block = PdxBlock::parse_file("test.txt", b"a = b c = 1").unwrap();
+        assert_eq!(block.display_file(false, false).to_string().trim_end(), "a = b c = 1");
+        assert!(PdxBlock::parse_file("test.txt", b"a").is_ok());
+    }
+
+    fn parse_string(src: &str) -> String {
+        let (block, _) = PdxBlock::parse_file_recovering("test.txt", src.as_bytes()).unwrap();
+        match &block.contents[..] {
+            [PdxBlockContent::Relation(PdxRelation {
+                value: PdxRelationValue::String(str), ..
+            }), ..] => str.to_string(),
+            contents => panic!("expected a string relation, found {:?}", contents),
+        }
+    }
+
+    #[test]
+    fn decodes_escapes_in_quoted_strings() {
+        assert_eq!(parse_string(r#"a = "say \"hi\"""#), r#"say "hi""#);
+        assert_eq!(parse_string(r#"a = "C:\\dir""#), r"C:\dir");
+        assert_eq!(parse_string(r#"a = "\\\"""#), r#"\""#);
+        // other characters keep their backslash.
+        assert_eq!(parse_string(r#"a = "line\nnext""#), r"line\nnext");
+        // as does a backslash ending an unterminated string.
+        assert_eq!(parse_string("a = \"b\\\nc = d"), r"b\");
+    }
+
+    #[test]
+    fn recovers_from_stray_closing_brackets() {
+        let (block, diagnostics) = parse_recovering("a = { b = c } }\nd = e");
+        assert_eq!(block, "a = { b = c } d = e");
+        assert_eq!(diagnostics, vec!["test.txt:1:15: Found unmatched closing bracket."]);
+
+        let err = PdxBlock::parse_file("test.txt", b"a = b }\nd = e").unwrap_err();
+        assert_eq!(err.to_string(), "test.txt:1:7: Found unmatched closing bracket.");
+    }
+
+    #[test]
+    fn recovers_from_unclosed_brackets() {
+        let (block, diagnostics) = parse_recovering("a = {\n    b = { c = d\n    e = f\n");
+        assert_eq!(block, "a = { b = { c = d e = f } }");
+        assert_eq!(diagnostics, vec![
+            "test.txt:2:9: Bracket is never closed.",
+            "test.txt:1:5: Bracket is never closed.",
+        ]);
+        assert!(PdxBlock::parse_file("test.txt", b"a = { b = c").is_err());
+    }
+
+    #[test]
+    fn recovers_from_unterminated_strings() {
+        // the string ends at the end of its line.
+        let (block, diagnostics) = parse_recovering("a = \"b c\nd = e");
+        assert_eq!(block, "a = \"b c\" d = e");
+        assert_eq!(diagnostics, vec!["test.txt:1:6: Found unterminated string."]);
+
+        let (block, diagnostics) = parse_recovering("a = @[ 1 + 2\nd = e");
+        assert_eq!(block, "a = @\\[ 1 + 2] d = e");
+        assert_eq!(diagnostics.len(), 1);
+
+        assert!(PdxBlock::parse_file("test.txt", b"a = \"b c\nd = e").is_err());
+    }
+
+    #[test]
+    fn recovers_from_malformed_members() {
+        // tokens that cannot start a member are skipped, including any block they open.
+        let (block, diagnostics) = parse_recovering("a = b\n= { c = d }\ne = f");
+        assert_eq!(block, "a = b e = f");
+        assert_eq!(diagnostics, vec![
+            "test.txt:2:1: Could not parse identifier.",
+            "test.txt:2:3: Could not parse identifier.",
+        ]);
+
+        let (block, diagnostics) = parse_recovering("a = { b = rgb { 1 x 2 } c = d }");
+        assert_eq!(block, "a = { b = rgb { 1 2 } c = d }");
+        assert_eq!(diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let (block, diagnostics) = parse_recovering("a = b }\nc = \"d\n= e\nf = { g = h");
+        assert_eq!(block, "a = b c = d e f = { g = h }");
+        assert_eq!(diagnostics.len(), 4, "{:?}", diagnostics);
     }
 }
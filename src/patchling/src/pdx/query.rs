@@ -0,0 +1,642 @@
+use crate::pdx::{PdxBlock, PdxRelation, PdxRelationValue, Visitor, WalkControl};
+use anyhow::*;
+use std::{cmp::Ordering, fmt, str::FromStr, sync::Arc};
+
+/// A compiled query that selects relations from a PDX tree.
+///
+/// Queries are a sequence of steps separated by `.`, each selecting relations by tag from the
+/// blocks selected by the previous step:
+///
+/// * `technology.modifier` selects `modifier` relations directly inside `technology` blocks.
+/// * `*` matches any tag, and `*_mult` matches any tag ending with `_mult`. Tags containing
+///   special characters can be quoted, such as `"2200.1.1"`.
+/// * `..` searches at any depth rather than only direct children, so `technology..cost` finds
+///   every `cost` anywhere inside `technology`, and a query starting with `..` searches the
+///   whole tree.
+/// * `[n]` selects the `n`th relation a step matched in each block, counting from zero. Negative
+///   indexes count from the end, so `modifier[-1]` is the last `modifier` in each block.
+/// * `[predicate]` keeps only the relations matching a predicate. `.tier >= 3` compares the
+///   value of a child relation, `> 0.1` compares the relation's own value, `.potential` checks
+///   that a child relation exists, and `relation == ">="` checks the relation's operator.
+///   Predicates may be combined with `and`, `or`, `not` and parentheses.
+///
+/// For example, `*[.tier >= 3].modifier.*_mult` selects every `*_mult` modifier of every
+/// definition with a tier of at least 3.
+#[derive(Clone, Debug)]
+pub struct PdxQuery {
+    source: Arc<str>,
+    steps: Vec<Step>,
+}
+
+/// A relation selected by a query.
+#[derive(Clone, Debug)]
+pub struct PdxQueryMatch<'a> {
+    /// The chain of tags from the root of the queried block, including the relation's own tag.
+    pub path: Vec<Arc<str>>,
+    pub relation: &'a PdxRelation,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Axis {
+    Child,
+    Descendant,
+}
+
+#[derive(Clone, Debug)]
+enum TagPattern {
+    Any,
+    Exact(Arc<str>),
+    Glob(Arc<str>),
+}
+impl TagPattern {
+    fn matches(&self, tag: &str) -> bool {
+        match self {
+            TagPattern::Any => true,
+            TagPattern::Exact(exact) => &**exact == tag,
+            TagPattern::Glob(glob) => glob_matches(glob, tag),
+        }
+    }
+}
+
+fn glob_matches(glob: &str, tag: &str) -> bool {
+    let mut parts = glob.split('*');
+    let first = parts.next().unwrap();
+    if !tag.starts_with(first) {
+        return false;
+    }
+    let mut rest = &tag[first.len()..];
+    let mut parts: Vec<_> = parts.collect();
+    let last = parts.pop().unwrap();
+    for part in parts {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[derive(Clone, Debug)]
+struct Step {
+    axis: Axis,
+    pattern: TagPattern,
+    filters: Vec<Filter>,
+}
+
+#[derive(Clone, Debug)]
+enum Filter {
+    Index(isize),
+    Predicate(Predicate),
+}
+
+#[derive(Clone, Debug)]
+enum Predicate {
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+    Exists(Operand),
+    Compare(Operand, CmpOp, Literal),
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    Value,
+    Relation,
+    Path(Vec<Step>),
+}
+
+#[derive(Copy, Clone, Debug)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+impl CmpOp {
+    fn test(&self, ord: Option<Ordering>) -> bool {
+        match ord {
+            Some(ord) => match self {
+                CmpOp::Eq => ord == Ordering::Equal,
+                CmpOp::Ne => ord != Ordering::Equal,
+                CmpOp::Lt => ord == Ordering::Less,
+                CmpOp::Le => ord != Ordering::Greater,
+                CmpOp::Gt => ord == Ordering::Greater,
+                CmpOp::Ge => ord != Ordering::Less,
+            },
+            None => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Literal {
+    Number(f64),
+    String(Arc<str>),
+}
+
+impl PdxQuery {
+    /// Parses a query.
+    pub fn parse(query: &str) -> Result<PdxQuery> {
+        let mut parser = QueryParser { src: query, pos: 0 };
+        parser.skip_whitespace();
+        let axis = if parser.eat("..") { Axis::Descendant } else { Axis::Child };
+        let steps = parser.parse_steps(axis)?;
+        parser.skip_whitespace();
+        if parser.pos != query.len() {
+            return Err(parser.error("Unexpected character"));
+        }
+        Ok(PdxQuery { source: query.into(), steps })
+    }
+
+    /// Finds every relation in a block matching this query, in the order they appear.
+    pub fn find<'a>(&self, block: &'a PdxBlock) -> Vec<PdxQueryMatch<'a>> {
+        let mut matches = Vec::new();
+        find_steps(&self.steps, block, &[], &mut matches);
+        matches
+    }
+}
+impl FromStr for PdxQuery {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        PdxQuery::parse(s)
+    }
+}
+impl fmt::Display for PdxQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl PdxBlock {
+    /// Finds every relation in this block matching a query. See [`PdxQuery`] for the syntax.
+    pub fn query(&self, query: &str) -> Result<Vec<PdxQueryMatch<'_>>> {
+        Ok(PdxQuery::parse(query)?.find(self))
+    }
+}
+
+fn find_steps<'a>(
+    steps: &[Step],
+    block: &'a PdxBlock,
+    path: &[Arc<str>],
+    out: &mut Vec<PdxQueryMatch<'a>>,
+) {
+    let (step, rest) = match steps.split_first() {
+        Some(x) => x,
+        None => return,
+    };
+
+    let mut candidates = Vec::new();
+    find_candidates(step, block, path, &mut candidates);
+    for filter in &step.filters {
+        candidates = match filter {
+            Filter::Index(idx) => {
+                let idx = if *idx < 0 { candidates.len() as isize + idx } else { *idx };
+                if idx >= 0 && (idx as usize) < candidates.len() {
+                    vec![candidates.swap_remove(idx as usize)]
+                } else {
+                    Vec::new()
+                }
+            }
+            Filter::Predicate(pred) => {
+                candidates.into_iter().filter(|x| check_predicate(pred, x.relation)).collect()
+            }
+        };
+    }
+
+    for candidate in candidates {
+        if rest.is_empty() {
+            out.push(candidate);
+        } else if let PdxRelationValue::Block(inner) = &candidate.relation.value {
+            find_steps(rest, inner, &candidate.path, out);
+        }
+    }
+}
+
+fn find_candidates<'a>(
+    step: &Step,
+    block: &'a PdxBlock,
+    path: &[Arc<str>],
+    out: &mut Vec<PdxQueryMatch<'a>>,
+) {
+    block.walk(&mut CandidateVisitor { step, prefix: path, out });
+}
+
+/// Collects the relations in a block matching a single step.
+struct CandidateVisitor<'s, 'a> {
+    step: &'s Step,
+    prefix: &'s [Arc<str>],
+    out: &'s mut Vec<PdxQueryMatch<'a>>,
+}
+impl<'s, 'a> Visitor<'a> for CandidateVisitor<'s, 'a> {
+    fn visit_relation(&mut self, path: &[Arc<str>], rel: &'a PdxRelation) -> WalkControl {
+        if self.step.pattern.matches(&rel.tag) {
+            let mut full_path = Vec::with_capacity(self.prefix.len() + path.len() + 1);
+            full_path.extend_from_slice(self.prefix);
+            full_path.extend_from_slice(path);
+            full_path.push(rel.tag.clone());
+            self.out.push(PdxQueryMatch { path: full_path, relation: rel });
+        }
+        match self.step.axis {
+            Axis::Child => WalkControl::SkipChildren,
+            Axis::Descendant => WalkControl::Continue,
+        }
+    }
+}
+
+fn find_relative<'a>(steps: &[Step], rel: &'a PdxRelation) -> Vec<PdxQueryMatch<'a>> {
+    let mut matches = Vec::new();
+    if let PdxRelationValue::Block(block) = &rel.value {
+        find_steps(steps, block, &[], &mut matches);
+    }
+    matches
+}
+
+fn check_predicate(pred: &Predicate, rel: &PdxRelation) -> bool {
+    match pred {
+        Predicate::And(a, b) => check_predicate(a, rel) && check_predicate(b, rel),
+        Predicate::Or(a, b) => check_predicate(a, rel) || check_predicate(b, rel),
+        Predicate::Not(a) => !check_predicate(a, rel),
+        Predicate::Exists(Operand::Path(steps)) => !find_relative(steps, rel).is_empty(),
+        Predicate::Exists(_) => true,
+        Predicate::Compare(Operand::Value, op, lit) => compare_value(&rel.value, *op, lit),
+        Predicate::Compare(Operand::Relation, op, lit) => {
+            compare_str(&rel.relation.to_string(), *op, lit)
+        }
+        Predicate::Compare(Operand::Path(steps), op, lit) => {
+            find_relative(steps, rel).iter().any(|x| compare_value(&x.relation.value, *op, lit))
+        }
+    }
+}
+
+fn compare_value(value: &PdxRelationValue, op: CmpOp, lit: &Literal) -> bool {
+    match (value, lit) {
+        (PdxRelationValue::Block(_), _) => false,
+        (PdxRelationValue::Numeric(num), Literal::Number(lit)) => op.test(num.partial_cmp(lit)),
+        (PdxRelationValue::String(str), Literal::Number(lit)) => match str.parse::<f64>() {
+            Ok(num) => op.test(num.partial_cmp(lit)),
+            Err(_) => false,
+        },
+        (_, Literal::Number(_)) => false,
+        (PdxRelationValue::String(str), lit) => compare_str(str, op, lit),
+        (value, lit) => compare_str(&value.to_string(), op, lit),
+    }
+}
+
+fn compare_str(str: &str, op: CmpOp, lit: &Literal) -> bool {
+    match lit {
+        Literal::String(lit) => op.test(Some(str.cmp(lit))),
+        Literal::Number(_) => false,
+    }
+}
+
+fn is_tag_char(ch: char) -> bool {
+    !ch.is_whitespace() && !matches!(ch, '.' | '[' | ']' | '"' | '(' | ')' | '=' | '!' | '<' | '>')
+}
+
+fn is_literal_char(ch: char) -> bool {
+    !ch.is_whitespace() && !matches!(ch, '[' | ']' | '"' | '(' | ')')
+}
+
+struct QueryParser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+impl<'a> QueryParser<'a> {
+    fn error(&self, message: &str) -> Error {
+        anyhow!("{} at position {} in query `{}`.", message, self.pos + 1, self.src)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, tok: &str) -> bool {
+        if self.rest().starts_with(tok) {
+            self.pos += tok.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let rest = self.rest();
+        if rest.starts_with(keyword) && !rest[keyword.len()..].starts_with(is_tag_char) {
+            self.pos += keyword.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|x| !f(x)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn parse_quoted(&mut self) -> Result<Option<String>> {
+        if !self.eat("\"") {
+            return Ok(None);
+        }
+        let mut str = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((idx, ch)) = chars.next() {
+            match ch {
+                '"' => {
+                    self.pos += idx + 1;
+                    return Ok(Some(str));
+                }
+                '\\' => match chars.next() {
+                    Some((_, ch)) => str.push(ch),
+                    None => break,
+                },
+                _ => str.push(ch),
+            }
+        }
+        Err(self.error("Unterminated string"))
+    }
+
+    fn parse_steps(&mut self, first_axis: Axis) -> Result<Vec<Step>> {
+        let mut steps = vec![self.parse_step(first_axis)?];
+        loop {
+            if self.eat("..") {
+                steps.push(self.parse_step(Axis::Descendant)?);
+            } else if self.eat(".") {
+                steps.push(self.parse_step(Axis::Child)?);
+            } else {
+                return Ok(steps);
+            }
+        }
+    }
+
+    fn parse_step(&mut self, axis: Axis) -> Result<Step> {
+        let pattern = if let Some(tag) = self.parse_quoted()? {
+            TagPattern::Exact(tag.into())
+        } else {
+            match self.take_while(is_tag_char) {
+                "" => return Err(self.error("Expected a tag")),
+                "*" => TagPattern::Any,
+                tag if tag.contains('*') => TagPattern::Glob(tag.into()),
+                tag => TagPattern::Exact(tag.into()),
+            }
+        };
+
+        let mut filters = Vec::new();
+        while self.eat("[") {
+            self.skip_whitespace();
+            let start = self.pos;
+            let index = self.take_while(|x| x == '-' || x.is_ascii_digit());
+            self.skip_whitespace();
+            match index.parse::<isize>() {
+                Ok(index) if self.peek() == Some(']') => filters.push(Filter::Index(index)),
+                _ => {
+                    self.pos = start;
+                    filters.push(Filter::Predicate(self.parse_or()?));
+                    self.skip_whitespace();
+                }
+            }
+            if !self.eat("]") {
+                return Err(self.error("Expected `]`"));
+            }
+        }
+
+        Ok(Step { axis, pattern, filters })
+    }
+
+    fn parse_or(&mut self) -> Result<Predicate> {
+        let mut pred = self.parse_and()?;
+        loop {
+            self.skip_whitespace();
+            if self.eat_keyword("or") {
+                pred = Predicate::Or(Box::new(pred), Box::new(self.parse_and()?));
+            } else {
+                return Ok(pred);
+            }
+        }
+    }
+
+    fn parse_and(&mut self) -> Result<Predicate> {
+        let mut pred = self.parse_unary()?;
+        loop {
+            self.skip_whitespace();
+            if self.eat_keyword("and") {
+                pred = Predicate::And(Box::new(pred), Box::new(self.parse_unary()?));
+            } else {
+                return Ok(pred);
+            }
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Predicate> {
+        self.skip_whitespace();
+        if self.eat_keyword("not") {
+            Ok(Predicate::Not(Box::new(self.parse_unary()?)))
+        } else if self.eat("(") {
+            let pred = self.parse_or()?;
+            self.skip_whitespace();
+            if !self.eat(")") {
+                return Err(self.error("Expected `)`"));
+            }
+            Ok(pred)
+        } else {
+            self.parse_comparison()
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Predicate> {
+        if let Some(op) = self.parse_cmp_op() {
+            return Ok(Predicate::Compare(Operand::Value, op, self.parse_literal()?));
+        }
+
+        let operand = if self.eat("..") {
+            Operand::Path(self.parse_steps(Axis::Descendant)?)
+        } else if self.eat(".") {
+            match self.peek() {
+                Some(ch) if ch == '"' || is_tag_char(ch) => {
+                    Operand::Path(self.parse_steps(Axis::Child)?)
+                }
+                _ => Operand::Value,
+            }
+        } else if self.eat_keyword("relation") {
+            Operand::Relation
+        } else {
+            return Err(self.error("Expected a predicate"));
+        };
+
+        self.skip_whitespace();
+        match self.parse_cmp_op() {
+            Some(op) => Ok(Predicate::Compare(operand, op, self.parse_literal()?)),
+            None => Ok(Predicate::Exists(operand)),
+        }
+    }
+
+    fn parse_cmp_op(&mut self) -> Option<CmpOp> {
+        const OPS: &[(&str, CmpOp)] = &[
+            ("==", CmpOp::Eq),
+            ("!=", CmpOp::Ne),
+            ("<=", CmpOp::Le),
+            (">=", CmpOp::Ge),
+            ("<", CmpOp::Lt),
+            (">", CmpOp::Gt),
+            ("=", CmpOp::Eq),
+        ];
+        OPS.iter().find(|(tok, _)| self.eat(tok)).map(|(_, op)| *op)
+    }
+
+    fn parse_literal(&mut self) -> Result<Literal> {
+        self.skip_whitespace();
+        if let Some(str) = self.parse_quoted()? {
+            return Ok(Literal::String(str.into()));
+        }
+        match self.take_while(is_literal_char) {
+            "" => Err(self.error("Expected a value")),
+            lit => match lit.parse::<f64>() {
+                Ok(num) => Ok(Literal::Number(num)),
+                Err(_) => Ok(Literal::String(lit.into())),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TECHS: &str = r#"
+        tech_a = {
+            tier = 1
+            modifier = { ship_speed_mult = 0.1 army_damage_add = 5 }
+        }
+        tech_b = {
+            tier = 3
+            is_rare = yes
+            modifier = { ship_speed_mult = 0.2 shield_mult = 0.3 }
+            cost = { energy = 100 }
+        }
+        tech_c = {
+            tier >= 4
+            modifier = { armor_mult = 0.4 }
+            modifier = { hull_mult = 0.5 }
+        }
+    "#;
+
+    fn find_paths(src: &str, query: &str) -> Vec<String> {
+        let block = PdxBlock::parse_file("test.txt", src.as_bytes()).unwrap();
+        let query = PdxQuery::parse(query).unwrap();
+        query.find(&block).iter().map(|x| x.path.join(".")).collect()
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_matches("*_mult", "ship_speed_mult"));
+        assert!(glob_matches("ship_*", "ship_speed_mult"));
+        assert!(glob_matches("ship*_mult", "ship_mult"));
+        assert!(glob_matches("a*b*c", "aXbYc"));
+        assert!(!glob_matches("*_mult", "army_damage_add"));
+        assert!(!glob_matches("a*b*c", "acb"));
+        assert!(!glob_matches("ab*ba", "aba"));
+
+        assert_eq!(find_paths(TECHS, "tech_a.modifier.*_mult"), vec![
+            "tech_a.modifier.ship_speed_mult"
+        ]);
+        assert_eq!(find_paths(TECHS, "tech_*.tier"), vec![
+            "tech_a.tier",
+            "tech_b.tier",
+            "tech_c.tier"
+        ]);
+        assert_eq!(find_paths(TECHS, "*.cost.*"), vec!["tech_b.cost.energy"]);
+    }
+
+    #[test]
+    fn searches_descendants() {
+        assert_eq!(find_paths(TECHS, "..shield_mult"), vec!["tech_b.modifier.shield_mult"]);
+        assert_eq!(find_paths(TECHS, "tech_c..*_mult"), vec![
+            "tech_c.modifier.armor_mult",
+            "tech_c.modifier.hull_mult"
+        ]);
+        assert_eq!(find_paths(TECHS, "..energy").len(), 1);
+        assert!(find_paths(TECHS, "tech_a.shield_mult").is_empty());
+    }
+
+    #[test]
+    fn selects_by_index() {
+        assert_eq!(find_paths(TECHS, "tech_c.modifier[0].*"), vec!["tech_c.modifier.armor_mult"]);
+        assert_eq!(find_paths(TECHS, "tech_c.modifier[-1].*"), vec!["tech_c.modifier.hull_mult"]);
+        assert_eq!(find_paths(TECHS, "tech_c.modifier[-2].*"), vec!["tech_c.modifier.armor_mult"]);
+        assert!(find_paths(TECHS, "tech_c.modifier[2]").is_empty());
+        assert!(find_paths(TECHS, "tech_c.modifier[-3]").is_empty());
+
+        // indexes count the relations matched in each block separately.
+        assert_eq!(find_paths(TECHS, "*.modifier[0].*_mult"), vec![
+            "tech_a.modifier.ship_speed_mult",
+            "tech_b.modifier.ship_speed_mult",
+            "tech_b.modifier.shield_mult",
+            "tech_c.modifier.armor_mult",
+        ]);
+    }
+
+    #[test]
+    fn filters_by_predicates() {
+        assert_eq!(find_paths(TECHS, "*[.is_rare]"), vec!["tech_b"]);
+        assert_eq!(find_paths(TECHS, "*[not .is_rare]"), vec!["tech_a", "tech_c"]);
+        assert_eq!(find_paths(TECHS, "*[.tier > 1 and .cost]"), vec!["tech_b"]);
+        assert_eq!(find_paths(TECHS, "*[.tier == 1 or .is_rare == yes]"), vec!["tech_a", "tech_b"]);
+        assert_eq!(find_paths(TECHS, "*[not (.tier == 1 or .is_rare)]"), vec!["tech_c"]);
+        assert_eq!(find_paths(TECHS, "*[..energy >= 100]"), vec!["tech_b"]);
+        assert_eq!(find_paths(TECHS, "..*_mult[> 0.25]"), vec![
+            "tech_b.modifier.shield_mult",
+            "tech_c.modifier.armor_mult",
+            "tech_c.modifier.hull_mult",
+        ]);
+        assert_eq!(find_paths(TECHS, "..*_mult[. <= 0.1]"), vec![
+            "tech_a.modifier.ship_speed_mult"
+        ]);
+    }
+
+    #[test]
+    fn filters_by_relation() {
+        assert_eq!(find_paths(TECHS, r#"*.tier[relation == ">="]"#), vec!["tech_c.tier"]);
+        assert_eq!(find_paths(TECHS, r#"*.tier[relation != ">="]"#), vec![
+            "tech_a.tier",
+            "tech_b.tier"
+        ]);
+    }
+
+    #[test]
+    fn runs_documented_example() {
+        assert_eq!(find_paths(TECHS, "*[.tier >= 3].modifier.*_mult"), vec![
+            "tech_b.modifier.ship_speed_mult",
+            "tech_b.modifier.shield_mult",
+            "tech_c.modifier.armor_mult",
+            "tech_c.modifier.hull_mult",
+        ]);
+    }
+
+    #[test]
+    fn parses_quoted_tags() {
+        let src = r#"events = { "2200.1.1" = { a = 1 } "2201.1.1" = { a = 2 } }"#;
+        assert_eq!(find_paths(src, r#"events."2200.1.1".a"#), vec!["events.2200.1.1.a"]);
+    }
+
+    #[test]
+    fn rejects_invalid_queries() {
+        for query in &["", "a.", "a[", "a[0", "a[.b", "a[(.b]", "a b", r#"a."b"#, "a[.b >]"] {
+            assert!(PdxQuery::parse(query).is_err(), "query `{}` should not parse", query);
+        }
+        let err = PdxQuery::parse("a]").unwrap_err().to_string();
+        assert!(err.contains("position 2"), "{}", err);
+    }
+}
@@ -0,0 +1,714 @@
+use crate::pdx::{
+    serde_pdx::Error, PdxBlock, PdxBlockContent, PdxRelation, PdxRelationType, PdxRelationValue,
+    PdxSpan,
+};
+use indexmap::IndexMap;
+use serde::{
+    de::{
+        value::{BorrowedStrDeserializer, SeqDeserializer},
+        DeserializeOwned, DeserializeSeed, EnumAccess, MapAccess, SeqAccess, VariantAccess,
+        Visitor,
+    },
+    forward_to_deserialize_any, Deserialize, Deserializer,
+};
+
+/// Deserializes a value from the contents of a parsed PDX file or block.
+pub fn from_block<'de, T: Deserialize<'de>>(block: &'de PdxBlock) -> Result<T, Error> {
+    T::deserialize(BlockDeserializer(block)).map_err(|e| e.at(&block.span))
+}
+
+/// Parses a PDX file and deserializes a value from its contents.
+pub fn from_slice<T: DeserializeOwned>(file_name: &str, file_data: &[u8]) -> Result<T, Error> {
+    let block =
+        PdxBlock::parse_file(file_name, file_data).map_err(|e| Error::new(format!("{:#}", e)))?;
+    from_block(&block)
+}
+
+/// Parses a string of PDX text and deserializes a value from it.
+pub fn from_str<T: DeserializeOwned>(source: &str) -> Result<T, Error> {
+    from_slice("<string>", source.as_bytes())
+}
+
+/// Returns whether a block should be deserialized as a map rather than a sequence.
+fn is_map_like(block: &PdxBlock) -> bool {
+    !block.contents.is_empty()
+        && block.contents.iter().all(|x| matches!(x, PdxBlockContent::Relation(_)))
+}
+
+fn check_relation(rel: &PdxRelation) -> Result<(), Error> {
+    if rel.relation != PdxRelationType::Normal {
+        let message =
+            format!("Key `{}` uses `{}`, but only `=` can be deserialized.", rel.tag, rel.relation);
+        return Err(Error::new(message).at(&rel.span));
+    }
+    Ok(())
+}
+
+/// Deserializes a PDX block as either a map or a sequence.
+struct BlockDeserializer<'de>(&'de PdxBlock);
+impl<'de> Deserializer<'de> for BlockDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if is_map_like(self.0) {
+            self.deserialize_map(visitor)
+        } else {
+            self.deserialize_seq(visitor)
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.0.contents.is_empty() {
+            visitor.visit_unit()
+        } else {
+            Err(Error::new("Expected an empty block."))
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(BlockSeqAccess { iter: self.0.contents.iter(), span: &self.0.span })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(RelationMapAccess::new(self.0)?)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match &self.0.contents[..] {
+            [PdxBlockContent::Relation(rel)] => visitor.visit_enum(RelationEnumAccess(rel)),
+            _ => Err(Error::new("Expected a block containing a single enum variant.")),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf identifier
+    }
+}
+
+struct BlockSeqAccess<'de> {
+    iter: std::slice::Iter<'de, PdxBlockContent>,
+    span: &'de Option<PdxSpan>,
+}
+impl<'de> SeqAccess<'de> for BlockSeqAccess<'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.iter.next() {
+            Some(PdxBlockContent::String(str)) => {
+                let value = seed.deserialize(StrDeserializer(str)).map_err(|e| e.at(self.span))?;
+                Ok(Some(value))
+            }
+            Some(PdxBlockContent::Relation(rel)) => {
+                let value =
+                    seed.deserialize(MemberDeserializer(rel)).map_err(|e| e.at(&rel.span))?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+/// Deserializes the relations in a block, grouping together relations with the same key.
+struct RelationMapAccess<'de> {
+    groups: indexmap::map::IntoIter<&'de str, Vec<&'de PdxRelation>>,
+    value: Option<Vec<&'de PdxRelation>>,
+}
+impl<'de> RelationMapAccess<'de> {
+    fn new(block: &'de PdxBlock) -> Result<Self, Error> {
+        let mut groups = IndexMap::new();
+        for content in &block.contents {
+            match content {
+                PdxBlockContent::Relation(rel) => {
+                    groups.entry(&*rel.tag).or_insert_with(Vec::new).push(rel);
+                }
+                PdxBlockContent::String(str) => {
+                    let message = format!("Found bare value `{}` in a block used as a map.", str);
+                    return Err(Error::new(message).at(&block.span));
+                }
+            }
+        }
+        Ok(RelationMapAccess { groups: groups.into_iter(), value: None })
+    }
+
+    fn single(rel: &'de PdxRelation) -> Self {
+        let mut groups = IndexMap::new();
+        groups.insert(&*rel.tag, vec![rel]);
+        RelationMapAccess { groups: groups.into_iter(), value: None }
+    }
+}
+impl<'de> MapAccess<'de> for RelationMapAccess<'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.groups.next() {
+            Some((key, rels)) => {
+                let first = rels[0];
+                let key = seed.deserialize(StrDeserializer(key)).map_err(|e| e.at(&first.span))?;
+                self.value = Some(rels);
+                Ok(Some(key))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let rels = self.value.take().expect("value requested before key");
+        let first = rels[0];
+        seed.deserialize(RelationsDeserializer(rels)).map_err(|e| e.at(&first.span))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.groups.len())
+    }
+}
+
+macro_rules! forward_to_single {
+    ($($method:ident($($arg:ident: $ty:ty),*))*) => {$(
+        fn $method<V: Visitor<'de>>(self, $($arg: $ty,)* visitor: V) -> Result<V::Value, Error> {
+            let rel = self.single()?;
+            ValueDeserializer(&rel.value).$method($($arg,)* visitor).map_err(|e| e.at(&rel.span))
+        }
+    )*};
+}
+
+/// Deserializes every relation in a block with a given key.
+///
+/// Keys that appear more than once can only be deserialized as sequences. Keys that appear once
+/// can also be deserialized as sequences, in which case a block of bare values is treated as the
+/// sequence, and any other value as its only element.
+struct RelationsDeserializer<'de>(Vec<&'de PdxRelation>);
+impl<'de> RelationsDeserializer<'de> {
+    fn single(&self) -> Result<&'de PdxRelation, Error> {
+        match &self.0[..] {
+            [rel] => {
+                check_relation(rel)?;
+                Ok(*rel)
+            }
+            _ => {
+                let message = format!("Key `{}` appears more than once.", self.0[0].tag);
+                Err(Error::new(message).at(&self.0[1].span))
+            }
+        }
+    }
+}
+impl<'de> Deserializer<'de> for RelationsDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.0.len() > 1 {
+            self.deserialize_seq(visitor)
+        } else {
+            let rel = self.single()?;
+            ValueDeserializer(&rel.value).deserialize_any(visitor).map_err(|e| e.at(&rel.span))
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if let [rel] = &self.0[..] {
+            let rel: &'de PdxRelation = rel;
+            check_relation(rel)?;
+            if let PdxRelationValue::Block(block) = &rel.value {
+                if !is_map_like(block) {
+                    let result = BlockDeserializer(block).deserialize_seq(visitor);
+                    return result.map_err(|e| e.at(&rel.span));
+                }
+            }
+        }
+        visitor.visit_seq(RelationsSeqAccess(self.0.into_iter()))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    forward_to_single! {
+        deserialize_bool() deserialize_i8() deserialize_i16() deserialize_i32() deserialize_i64()
+        deserialize_u8() deserialize_u16() deserialize_u32() deserialize_u64() deserialize_f32()
+        deserialize_f64() deserialize_char() deserialize_str() deserialize_string()
+        deserialize_bytes() deserialize_byte_buf() deserialize_option() deserialize_unit()
+        deserialize_map() deserialize_identifier()
+        deserialize_unit_struct(name: &'static str)
+        deserialize_newtype_struct(name: &'static str)
+        deserialize_struct(name: &'static str, fields: &'static [&'static str])
+        deserialize_enum(name: &'static str, variants: &'static [&'static str])
+    }
+}
+
+struct RelationsSeqAccess<'de>(std::vec::IntoIter<&'de PdxRelation>);
+impl<'de> SeqAccess<'de> for RelationsSeqAccess<'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.0.next() {
+            Some(rel) => {
+                check_relation(rel)?;
+                let value = seed.deserialize(ValueDeserializer(&rel.value));
+                Ok(Some(value.map_err(|e| e.at(&rel.span))?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+/// Deserializes a relation found in a block that is used as a sequence, as either a map with a
+/// single key or an enum variant.
+struct MemberDeserializer<'de>(&'de PdxRelation);
+impl<'de> Deserializer<'de> for MemberDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(RelationMapAccess::single(self.0))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(RelationEnumAccess(self.0))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Deserializes an enum variant written as `variant = value`.
+struct RelationEnumAccess<'de>(&'de PdxRelation);
+impl<'de> EnumAccess<'de> for RelationEnumAccess<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let rel = self.0;
+        let variant = seed.deserialize(StrDeserializer(&rel.tag)).map_err(|e| e.at(&rel.span))?;
+        Ok((variant, self))
+    }
+}
+impl<'de> VariantAccess<'de> for RelationEnumAccess<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(RelationsDeserializer(vec![self.0]))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        RelationsDeserializer(vec![self.0]).deserialize_seq(visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        RelationsDeserializer(vec![self.0]).deserialize_struct("", fields, visitor)
+    }
+}
+
+macro_rules! dispatch_value {
+    ($($method:ident($($arg:ident: $ty:ty),*))*) => {$(
+        fn $method<V: Visitor<'de>>(self, $($arg: $ty,)* visitor: V) -> Result<V::Value, Error> {
+            match self.0 {
+                PdxRelationValue::Block(block) => BlockDeserializer(block)
+                    .$method($($arg,)* visitor)
+                    .map_err(|e| e.at(&block.span)),
+                PdxRelationValue::String(str) => StrDeserializer(str).$method($($arg,)* visitor),
+                _ => ScalarDeserializer(self.0).$method($($arg,)* visitor),
+            }
+        }
+    )*};
+}
+
+/// Deserializes the value of a relation.
+struct ValueDeserializer<'de>(&'de PdxRelationValue);
+impl<'de> Deserializer<'de> for ValueDeserializer<'de> {
+    type Error = Error;
+
+    dispatch_value! {
+        deserialize_any() deserialize_bool() deserialize_i8() deserialize_i16() deserialize_i32()
+        deserialize_i64() deserialize_u8() deserialize_u16() deserialize_u32() deserialize_u64()
+        deserialize_f32() deserialize_f64() deserialize_char() deserialize_str()
+        deserialize_string() deserialize_bytes() deserialize_byte_buf() deserialize_option()
+        deserialize_unit() deserialize_seq() deserialize_map() deserialize_identifier()
+        deserialize_ignored_any()
+        deserialize_unit_struct(name: &'static str)
+        deserialize_newtype_struct(name: &'static str)
+        deserialize_tuple(len: usize)
+        deserialize_tuple_struct(name: &'static str, len: usize)
+        deserialize_struct(name: &'static str, fields: &'static [&'static str])
+        deserialize_enum(name: &'static str, variants: &'static [&'static str])
+    }
+}
+
+macro_rules! parse_str {
+    ($($method:ident($ty:ty) => $visit:ident)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            match self.0.parse::<$ty>() {
+                Ok(value) => visitor.$visit(value),
+                Err(_) => visitor.visit_borrowed_str(self.0),
+            }
+        }
+    )*};
+}
+
+/// Deserializes a bare string, which may also be used as a boolean or a number.
+struct StrDeserializer<'de>(&'de str);
+impl<'de> Deserializer<'de> for StrDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_str(self.0)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            "yes" => visitor.visit_bool(true),
+            "no" => visitor.visit_bool(false),
+            _ => visitor.visit_borrowed_str(self.0),
+        }
+    }
+
+    parse_str! {
+        deserialize_i8(i64) => visit_i64
+        deserialize_i16(i64) => visit_i64
+        deserialize_i32(i64) => visit_i64
+        deserialize_i64(i64) => visit_i64
+        deserialize_u8(u64) => visit_u64
+        deserialize_u16(u64) => visit_u64
+        deserialize_u32(u64) => visit_u64
+        deserialize_u64(u64) => visit_u64
+        deserialize_f32(f64) => visit_f64
+        deserialize_f64(f64) => visit_f64
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(BorrowedStrDeserializer::new(self.0))
+    }
+
+    forward_to_deserialize_any! {
+        char str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+/// Deserializes a relation value that is neither a block nor a string.
+struct ScalarDeserializer<'de>(&'de PdxRelationValue);
+impl<'de> Deserializer<'de> for ScalarDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            PdxRelationValue::Numeric(num) => {
+                if num.fract() == 0.0 && num.abs() <= i64::MAX as f64 {
+                    visitor.visit_i64(*num as i64)
+                } else {
+                    visitor.visit_f64(*num)
+                }
+            }
+            PdxRelationValue::Variable(name) => visitor.visit_string(format!("@{}", name)),
+            PdxRelationValue::VariableExpr(expr) => visitor.visit_string(format!("@[{}]", expr)),
+            PdxRelationValue::Color { components, .. } => {
+                visitor.visit_seq(SeqDeserializer::new(components.iter().copied()))
+            }
+            PdxRelationValue::Date { .. } => visitor.visit_string(self.0.to_string()),
+            _ => ValueDeserializer(self.0).deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            PdxRelationValue::Numeric(num) => visitor.visit_f64(*num),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            PdxRelationValue::Numeric(num) => visitor.visit_string(num.to_string()),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 char bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Modifier {
+        ship_speed_mult: Option<f64>,
+        #[serde(default)]
+        armor_add: i32,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Technology {
+        tier: u32,
+        is_rare: bool,
+        #[serde(default)]
+        is_dangerous: bool,
+        prerequisites: Vec<String>,
+        modifier: Vec<Modifier>,
+        cost: BTreeMap<String, f64>,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    enum Effect {
+        Stop,
+        Speed(f64),
+        Teleport { x: i32, y: i32 },
+    }
+
+    #[test]
+    fn deserializes_nested_structs() {
+        let tech: Technology = from_str(
+            r#"
+                tier = 2
+                is_rare = yes
+                prerequisites = { tech_a tech_b }
+                modifier = { ship_speed_mult = 0.1 }
+                cost = { energy = 100 minerals = 2.5 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(tech, Technology {
+            tier: 2,
+            is_rare: true,
+            is_dangerous: false,
+            prerequisites: vec!["tech_a".to_string(), "tech_b".to_string()],
+            modifier: vec![Modifier { ship_speed_mult: Some(0.1), armor_add: 0 }],
+            cost: vec![("energy".to_string(), 100.0), ("minerals".to_string(), 2.5)]
+                .into_iter()
+                .collect(),
+        });
+    }
+
+    #[test]
+    fn deserializes_repeated_keys_as_vec() {
+        let tech: Technology = from_str(
+            r#"
+                tier = 1
+                is_rare = no
+                prerequisites = { }
+                modifier = { ship_speed_mult = 0.1 }
+                modifier = { armor_add = 5 }
+                cost = { }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(tech.modifier, vec![
+            Modifier { ship_speed_mult: Some(0.1), armor_add: 0 },
+            Modifier { ship_speed_mult: None, armor_add: 5 },
+        ]);
+        assert!(tech.prerequisites.is_empty());
+
+        let values: BTreeMap<String, Vec<u32>> = from_str("a = 1 a = 2 b = 3").unwrap();
+        assert_eq!(values["a"], vec![1, 2]);
+        assert_eq!(values["b"], vec![3]);
+    }
+
+    #[test]
+    fn deserializes_bools() {
+        let values: BTreeMap<String, bool> = from_str("a = yes b = no").unwrap();
+        assert!(values["a"]);
+        assert!(!values["b"]);
+
+        let err = from_str::<BTreeMap<String, bool>>("a = maybe").unwrap_err().to_string();
+        assert!(err.contains("maybe"), "{}", err);
+    }
+
+    #[test]
+    fn deserializes_enums() {
+        let effects: BTreeMap<String, Effect> = from_str(
+            r#"
+                a = stop
+                b = { speed = 1.5 }
+                c = { teleport = { x = 1 y = -2 } }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(effects["a"], Effect::Stop);
+        assert_eq!(effects["b"], Effect::Speed(1.5));
+        assert_eq!(effects["c"], Effect::Teleport { x: 1, y: -2 });
+
+        // a sequence of enums may mix bare variants with variants written as relations.
+        let effects: BTreeMap<String, Vec<Effect>> =
+            from_str("effects = { stop speed = 2 stop }").unwrap();
+        assert_eq!(effects["effects"], vec![Effect::Stop, Effect::Speed(2.0), Effect::Stop]);
+    }
+
+    #[test]
+    fn reports_error_spans() {
+        let err = from_str::<Technology>("tier = 1\nis_rare = sometimes\n").unwrap_err();
+        assert_eq!(err.span().unwrap().line, 2);
+        let err = err.to_string();
+        assert!(err.starts_with("<string>:2:"), "{}", err);
+        assert!(err.contains("sometimes"), "{}", err);
+
+        // errors in nested blocks point at the innermost relation.
+        let err = from_str::<BTreeMap<String, Modifier>>("m = {\n\n  armor_add = lots\n}")
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("<string>:3:"), "{}", err);
+
+        // a key that appears more than once points at its second occurrence.
+        let err = from_str::<BTreeMap<String, u32>>("a = 1\nb = 2\na = 3").unwrap_err().to_string();
+        assert!(err.starts_with("<string>:3:"), "{}", err);
+        assert!(err.contains("more than once"), "{}", err);
+
+        let err = from_str::<BTreeMap<String, u32>>("a = 1\nb >= 2").unwrap_err().to_string();
+        assert!(err.starts_with("<string>:2:"), "{}", err);
+        assert!(err.contains(">="), "{}", err);
+    }
+}
@@ -0,0 +1,62 @@
+//! A serde data format for PDX files.
+//!
+//! Blocks made only of relations map to structs and maps, and blocks made of bare values map to
+//! sequences. Keys that appear more than once in a block map to a `Vec` with one element per
+//! occurrence, and `yes` and `no` map to `bool`.
+
+mod de;
+mod ser;
+
+pub use de::{from_block, from_slice, from_str};
+pub use ser::{to_block, to_string};
+
+use crate::pdx::PdxSpan;
+use std::fmt;
+
+/// An error that occurred while serializing or deserializing PDX data.
+#[derive(Clone, Debug)]
+pub struct Error {
+    span: Option<PdxSpan>,
+    message: String,
+}
+
+/// An alias for `Result` with the error type [`Error`].
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    fn new(message: impl fmt::Display) -> Self {
+        Error { span: None, message: message.to_string() }
+    }
+
+    /// Returns the location in the PDX source that caused this error, if it is known.
+    pub fn span(&self) -> Option<&PdxSpan> {
+        self.span.as_ref()
+    }
+
+    /// Sets the location of this error, unless an inner value already set a more precise one.
+    fn at(mut self, span: &Option<PdxSpan>) -> Self {
+        if self.span.is_none() {
+            self.span = span.clone();
+        }
+        self
+    }
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.span {
+            Some(span) => write!(f, "{}: {}", span, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+impl std::error::Error for Error {}
+impl serde::de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::new(msg)
+    }
+}
+impl serde::ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::new(msg)
+    }
+}
@@ -0,0 +1,442 @@
+use crate::pdx::{
+    serde_pdx::Error, PdxBlock, PdxBlockContent, PdxRelation, PdxRelationType, PdxRelationValue,
+};
+use serde::{ser, Serialize};
+use std::sync::Arc;
+
+/// Serializes a value into a PDX block. Only maps and structs can be serialized this way.
+pub fn to_block<T: Serialize + ?Sized>(value: &T) -> Result<PdxBlock, Error> {
+    match value.serialize(ValueSerializer)? {
+        Serialized::Value(PdxRelationValue::Block(block)) => Ok(block),
+        _ => Err(Error::new("Only maps and structs can be serialized as a PDX file.")),
+    }
+}
+
+/// Serializes a value into pretty printed PDX text.
+pub fn to_string<T: Serialize + ?Sized>(value: &T) -> Result<String, Error> {
+    Ok(to_block(value)?.display_file(false, true).to_string())
+}
+
+/// A serialized value, before it is placed in a block.
+enum Serialized {
+    Value(PdxRelationValue),
+    /// A sequence, which is written as repeated keys if it contains blocks, and as a block of
+    /// bare values otherwise.
+    Seq(Vec<PdxRelationValue>),
+    /// A missing value, such as `None`, which is left out of its block entirely.
+    Skip,
+}
+
+fn relation(tag: Arc<str>, value: PdxRelationValue) -> PdxBlockContent {
+    PdxBlockContent::Relation(PdxRelation {
+        tag,
+        relation: PdxRelationType::Normal,
+        value,
+        span: None,
+    })
+}
+
+fn list_block(items: Vec<PdxRelationValue>) -> Result<PdxBlock, Error> {
+    let mut contents = Vec::new();
+    for item in items {
+        match item {
+            PdxRelationValue::String(str) => contents.push(PdxBlockContent::String(str)),
+            // a block with a single key, such as an enum variant, can be written inline.
+            PdxRelationValue::Block(mut block) if block.contents.len() == 1 => {
+                contents.push(block.contents.pop().unwrap())
+            }
+            PdxRelationValue::Block(_) => {
+                return Err(Error::new(
+                    "Sequences of blocks can only be used as the value of a key.",
+                ))
+            }
+            item => contents.push(PdxBlockContent::String(item.to_string().into())),
+        }
+    }
+    Ok(PdxBlock::new(contents))
+}
+
+fn into_value(value: Serialized) -> Result<Option<PdxRelationValue>, Error> {
+    match value {
+        Serialized::Value(value) => Ok(Some(value)),
+        Serialized::Seq(items) => Ok(Some(PdxRelationValue::Block(list_block(items)?))),
+        Serialized::Skip => Ok(None),
+    }
+}
+
+fn push_field(
+    contents: &mut Vec<PdxBlockContent>,
+    tag: Arc<str>,
+    value: Serialized,
+) -> Result<(), Error> {
+    match value {
+        Serialized::Seq(items)
+            if !items.is_empty()
+                && items.iter().all(|x| matches!(x, PdxRelationValue::Block(_))) =>
+        {
+            for item in items {
+                contents.push(relation(tag.clone(), item));
+            }
+        }
+        value => {
+            if let Some(value) = into_value(value)? {
+                contents.push(relation(tag, value));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn variant_block(variant: &'static str, value: Serialized) -> Result<Serialized, Error> {
+    let mut contents = Vec::new();
+    push_field(&mut contents, variant.into(), value)?;
+    Ok(Serialized::Value(PdxRelationValue::Block(PdxBlock::new(contents))))
+}
+
+macro_rules! serialize_numeric {
+    ($($method:ident($ty:ty))*) => {$(
+        fn $method(self, v: $ty) -> Result<Serialized, Error> {
+            self.serialize_f64(v as f64)
+        }
+    )*};
+}
+
+struct ValueSerializer;
+impl ser::Serializer for ValueSerializer {
+    type Ok = Serialized;
+    type Error = Error;
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = SeqSerializer;
+    type SerializeTupleStruct = SeqSerializer;
+    type SerializeTupleVariant = VariantSerializer<SeqSerializer>;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = MapSerializer;
+    type SerializeStructVariant = VariantSerializer<MapSerializer>;
+
+    fn serialize_bool(self, v: bool) -> Result<Serialized, Error> {
+        Ok(Serialized::Value(PdxRelationValue::String(if v { "yes" } else { "no" }.into())))
+    }
+
+    serialize_numeric! {
+        serialize_i8(i8) serialize_i16(i16) serialize_i32(i32) serialize_i64(i64)
+        serialize_u8(u8) serialize_u16(u16) serialize_u32(u32) serialize_u64(u64)
+        serialize_f32(f32)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Serialized, Error> {
+        if !v.is_finite() {
+            return Err(Error::new(format!("{} cannot be represented in a PDX file.", v)));
+        }
+        Ok(Serialized::Value(PdxRelationValue::Numeric(v)))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Serialized, Error> {
+        Ok(Serialized::Value(PdxRelationValue::String(v.to_string().into())))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Serialized, Error> {
+        Ok(Serialized::Value(PdxRelationValue::String(v.into())))
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Serialized, Error> {
+        Err(Error::new("Byte arrays cannot be represented in a PDX file."))
+    }
+
+    fn serialize_none(self) -> Result<Serialized, Error> {
+        Ok(Serialized::Skip)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Serialized, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Serialized, Error> {
+        Ok(Serialized::Skip)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Serialized, Error> {
+        Ok(Serialized::Skip)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Serialized, Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Serialized, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Serialized, Error> {
+        variant_block(variant, value.serialize(self)?)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqSerializer, Error> {
+        Ok(SeqSerializer(Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SeqSerializer, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SeqSerializer, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<VariantSerializer<SeqSerializer>, Error> {
+        Ok(VariantSerializer { variant, inner: self.serialize_seq(Some(len))? })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapSerializer, Error> {
+        Ok(MapSerializer { contents: Vec::new(), key: None })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<MapSerializer, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<VariantSerializer<MapSerializer>, Error> {
+        Ok(VariantSerializer { variant, inner: self.serialize_map(Some(len))? })
+    }
+}
+
+struct SeqSerializer(Vec<PdxRelationValue>);
+impl ser::SerializeSeq for SeqSerializer {
+    type Ok = Serialized;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        if let Some(value) = into_value(value.serialize(ValueSerializer)?)? {
+            self.0.push(value);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Serialized, Error> {
+        Ok(Serialized::Seq(self.0))
+    }
+}
+impl ser::SerializeTuple for SeqSerializer {
+    type Ok = Serialized;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Serialized, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+impl ser::SerializeTupleStruct for SeqSerializer {
+    type Ok = Serialized;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Serialized, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+struct MapSerializer {
+    contents: Vec<PdxBlockContent>,
+    key: Option<Arc<str>>,
+}
+impl ser::SerializeMap for MapSerializer {
+    type Ok = Serialized;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(match key.serialize(ValueSerializer)? {
+            Serialized::Value(PdxRelationValue::String(str)) => str,
+            Serialized::Value(PdxRelationValue::Numeric(num)) => num.to_string().into(),
+            _ => return Err(Error::new("Keys must be strings or numbers.")),
+        });
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self.key.take().expect("value serialized before key");
+        push_field(&mut self.contents, key, value.serialize(ValueSerializer)?)
+    }
+
+    fn end(self) -> Result<Serialized, Error> {
+        Ok(Serialized::Value(PdxRelationValue::Block(PdxBlock::new(self.contents))))
+    }
+}
+impl ser::SerializeStruct for MapSerializer {
+    type Ok = Serialized;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        push_field(&mut self.contents, key.into(), value.serialize(ValueSerializer)?)
+    }
+
+    fn end(self) -> Result<Serialized, Error> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+struct VariantSerializer<S> {
+    variant: &'static str,
+    inner: S,
+}
+impl ser::SerializeTupleVariant for VariantSerializer<SeqSerializer> {
+    type Ok = Serialized;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(&mut self.inner, value)
+    }
+
+    fn end(self) -> Result<Serialized, Error> {
+        variant_block(self.variant, ser::SerializeSeq::end(self.inner)?)
+    }
+}
+impl ser::SerializeStructVariant for VariantSerializer<MapSerializer> {
+    type Ok = Serialized;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        ser::SerializeStruct::serialize_field(&mut self.inner, key, value)
+    }
+
+    fn end(self) -> Result<Serialized, Error> {
+        variant_block(self.variant, ser::SerializeMap::end(self.inner)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdx::serde_pdx::from_str;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Modifier {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ship_speed_mult: Option<f64>,
+        #[serde(default)]
+        armor_add: i32,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    enum Effect {
+        Stop,
+        Speed(f64),
+        Teleport { x: i32, y: i32 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Technology {
+        tier: u32,
+        is_rare: bool,
+        prerequisites: Vec<String>,
+        modifier: Vec<Modifier>,
+        effect: Effect,
+        on_research: Vec<Effect>,
+    }
+
+    fn tech() -> Technology {
+        Technology {
+            tier: 3,
+            is_rare: true,
+            prerequisites: vec!["tech_a".to_string(), "tech_b".to_string()],
+            modifier: vec![Modifier { ship_speed_mult: Some(0.1), armor_add: 0 }, Modifier {
+                ship_speed_mult: None,
+                armor_add: 5,
+            }],
+            effect: Effect::Teleport { x: 1, y: -2 },
+            on_research: vec![Effect::Stop, Effect::Speed(2.0)],
+        }
+    }
+
+    #[test]
+    fn serializes_structs() {
+        let expected = concat!(
+            "tier = 3\n",
+            "is_rare = yes\n",
+            "prerequisites = {\n    tech_a\n    tech_b\n}\n",
+            "modifier = {\n    ship_speed_mult = 0.1\n    armor_add = 0\n}\n",
+            "modifier = {\n    armor_add = 5\n}\n",
+            "effect = {\n    teleport = {\n        x = 1\n        y = -2\n    }\n}\n",
+            "on_research = {\n    stop\n    speed = 2\n}\n",
+        );
+        assert_eq!(to_string(&tech()).unwrap(), expected);
+    }
+
+    #[test]
+    fn serializes_bools_as_yes_and_no() {
+        let map: BTreeMap<_, _> = vec![("a", true), ("b", false)].into_iter().collect();
+        let text = to_string(&map).unwrap();
+        assert_eq!(text, "a = yes\nb = no\n");
+        assert_eq!(
+            from_str::<BTreeMap<String, bool>>(&text).unwrap(),
+            map.into_iter().map(|(k, v)| (k.to_string(), v)).collect()
+        );
+    }
+
+    #[test]
+    fn rejects_unrepresentable_values() {
+        let err = to_string(&Modifier { ship_speed_mult: Some(f64::NAN), armor_add: 0 });
+        assert!(err.unwrap_err().to_string().contains("NaN"));
+
+        let err = to_string(&vec![1, 2, 3]).unwrap_err().to_string();
+        assert!(err.contains("Only maps and structs"), "{}", err);
+
+        // blocks can only be repeated as the value of a key, not nested in another sequence.
+        let nested: BTreeMap<_, _> =
+            vec![("lists", vec![vec![tech(), tech()]])].into_iter().collect();
+        let err = to_string(&nested).unwrap_err().to_string();
+        assert!(err.contains("Sequences of blocks"), "{}", err);
+    }
+
+    #[test]
+    fn round_trips() {
+        let tech = tech();
+        assert_eq!(from_str::<Technology>(&to_string(&tech).unwrap()).unwrap(), tech);
+    }
+}
@@ -0,0 +1,349 @@
+use crate::pdx::{PdxBlock, PdxBlockContent, PdxRelationValue};
+use anyhow::*;
+use std::{collections::HashMap, sync::Arc};
+
+/// The game stores script values as fixed-point numbers with three decimal places.
+const FIXED_POINT_SCALE: f64 = 1000.0;
+
+/// Converts a value to the game's fixed-point representation, truncating any further decimal
+/// places. Values that are already within rounding error of a representable value are kept as
+/// they are, so `0.3` does not become `0.299`.
+fn to_fixed_point(value: f64) -> f64 {
+    let scaled = value * FIXED_POINT_SCALE;
+    let rounded = scaled.round();
+    if (scaled - rounded).abs() < 1e-6 {
+        rounded / FIXED_POINT_SCALE
+    } else {
+        scaled.trunc() / FIXED_POINT_SCALE
+    }
+}
+
+/// A scope of scripted variables, used to find the effective values of `@variable` references and
+/// `@[ ... ]` inline math expressions.
+///
+/// Scopes can be layered, so that the variables defined at the top of a file can be added on top
+/// of the global variables defined in `common/scripted_variables`.
+#[derive(Clone, Debug, Default)]
+pub struct PdxVariables {
+    parent: Option<Arc<PdxVariables>>,
+    values: HashMap<Arc<str>, PdxRelationValue>,
+}
+impl PdxVariables {
+    /// Creates a new empty scope.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Creates a new empty scope layered on top of another.
+    pub fn with_parent(parent: Arc<PdxVariables>) -> Self {
+        PdxVariables { parent: Some(parent), values: HashMap::new() }
+    }
+
+    /// Adds all `@name = value` definitions at the top level of a block to this scope.
+    ///
+    /// Definitions are evaluated in order, so they may refer to variables defined before them.
+    pub fn add_definitions(&mut self, block: &PdxBlock) -> Result<()> {
+        for content in &block.contents {
+            if let PdxBlockContent::Relation(rel) = content {
+                if let Some(name) = rel.tag.strip_prefix('@') {
+                    let value = match self.resolve(&rel.value) {
+                        Ok(value) => value,
+                        Err(e) => match &rel.span {
+                            Some(span) => bail!("{}: {}", span, e),
+                            None => return Err(e),
+                        },
+                    };
+                    self.values.insert(name.into(), value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the value of a variable, without its leading `@`.
+    pub fn get(&self, name: &str) -> Option<&PdxRelationValue> {
+        match self.values.get(name) {
+            Some(value) => Some(value),
+            None => self.parent.as_ref().and_then(|x| x.get(name)),
+        }
+    }
+
+    /// Returns the effective value of a relation value, replacing variables with their values
+    /// and evaluating inline math.
+    pub fn resolve(&self, value: &PdxRelationValue) -> Result<PdxRelationValue> {
+        match value {
+            PdxRelationValue::Variable(name) => match self.get(name) {
+                Some(value) => Ok(value.clone()),
+                None => bail!("Variable @{} is not defined.", name),
+            },
+            PdxRelationValue::VariableExpr(expr) => Ok(PdxRelationValue::Numeric(self.eval(expr)?)),
+            _ => Ok(value.clone()),
+        }
+    }
+
+    /// Returns a relation value with the variables defined in this scope itself replaced by their
+    /// values, or `None` if it does not refer to any of them. Variables from parent scopes are
+    /// left as they are.
+    ///
+    /// This allows a value to be written outside of the file that defines its variables.
+    pub fn resolve_local(&self, value: &PdxRelationValue) -> Result<Option<PdxRelationValue>> {
+        match value {
+            PdxRelationValue::Variable(name) => Ok(self.values.get(name).cloned()),
+            PdxRelationValue::VariableExpr(expr) => {
+                let is_local = |token: &Token<'_>| match token {
+                    Token::Ident(name) => {
+                        self.values.contains_key(name.strip_prefix('@').unwrap_or(name))
+                    }
+                    _ => false,
+                };
+                if tokenize(expr)?.iter().any(is_local) {
+                    Ok(Some(PdxRelationValue::Numeric(self.eval(expr)?)))
+                } else {
+                    Ok(None)
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the effective numeric value of a relation value, if it has one.
+    pub fn resolve_numeric(&self, value: &PdxRelationValue) -> Result<Option<f64>> {
+        match self.resolve(value)? {
+            PdxRelationValue::Numeric(num) => Ok(Some(num)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Evaluates the contents of an inline math expression.
+    pub fn eval(&self, expr: &str) -> Result<f64> {
+        let mut ctx = ExprCtx { vars: self, expr, tokens: tokenize(expr)?, cursor: 0 };
+        let value = ctx.parse_sum()?;
+        ensure!(ctx.cursor == ctx.tokens.len(), "Unexpected token in expression: {}", expr);
+        Ok(value)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token<'a> {
+    Number(f64),
+    Ident(&'a str),
+    Op(char),
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.char_indices().peekable();
+    while let Some(&(start, ch)) = chars.peek() {
+        match ch {
+            ' ' | '\t' | '\r' | '\n' => {
+                chars.next();
+            }
+            '+' | '-' | '*' | '/' | '%' | '(' | ')' => {
+                tokens.push(Token::Op(ch));
+                chars.next();
+            }
+            '0'..='9' | '.' => {
+                let mut end = start;
+                while let Some(&(idx, '0'..='9')) | Some(&(idx, '.')) = chars.peek() {
+                    end = idx + 1;
+                    chars.next();
+                }
+                match expr[start..end].parse() {
+                    Ok(num) => tokens.push(Token::Number(num)),
+                    Err(_) => bail!("Invalid number in expression: {}", &expr[start..end]),
+                }
+            }
+            'a'..='z' | 'A'..='Z' | '_' | '@' => {
+                let mut end = start;
+                while let Some(&(idx, ch)) = chars.peek() {
+                    match ch {
+                        'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '@' => {
+                            end = idx + ch.len_utf8();
+                            chars.next();
+                        }
+                        _ => break,
+                    }
+                }
+                tokens.push(Token::Ident(&expr[start..end]));
+            }
+            _ => bail!("Unexpected character in expression: {:?}", ch),
+        }
+    }
+    Ok(tokens)
+}
+
+struct ExprCtx<'a> {
+    vars: &'a PdxVariables,
+    expr: &'a str,
+    tokens: Vec<Token<'a>>,
+    cursor: usize,
+}
+impl<'a> ExprCtx<'a> {
+    fn check_op(&mut self, op: char) -> bool {
+        if self.tokens.get(self.cursor) == Some(&Token::Op(op)) {
+            self.cursor += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_sum(&mut self) -> Result<f64> {
+        let mut value = self.parse_product()?;
+        loop {
+            if self.check_op('+') {
+                value = to_fixed_point(value + self.parse_product()?);
+            } else if self.check_op('-') {
+                value = to_fixed_point(value - self.parse_product()?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn parse_product(&mut self) -> Result<f64> {
+        let mut value = self.parse_unary()?;
+        loop {
+            if self.check_op('*') {
+                value = to_fixed_point(value * self.parse_unary()?);
+            } else if self.check_op('/') {
+                let rhs = self.parse_unary()?;
+                ensure!(rhs != 0.0, "Division by zero in expression: {}", self.expr);
+                value = to_fixed_point(value / rhs);
+            } else if self.check_op('%') {
+                let rhs = self.parse_unary()?;
+                ensure!(rhs != 0.0, "Division by zero in expression: {}", self.expr);
+                value = to_fixed_point(value % rhs);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<f64> {
+        if self.check_op('-') {
+            Ok(-self.parse_unary()?)
+        } else if self.check_op('+') {
+            self.parse_unary()
+        } else {
+            self.parse_atom()
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<f64> {
+        match self.tokens.get(self.cursor).cloned() {
+            Some(Token::Number(num)) => {
+                self.cursor += 1;
+                Ok(to_fixed_point(num))
+            }
+            Some(Token::Ident(name)) => {
+                self.cursor += 1;
+                let name = name.strip_prefix('@').unwrap_or(name);
+                match self.vars.get(name) {
+                    Some(PdxRelationValue::Numeric(num)) => Ok(to_fixed_point(*num)),
+                    Some(_) => bail!("Variable @{} is not numeric.", name),
+                    None => bail!("Variable @{} is not defined.", name),
+                }
+            }
+            Some(Token::Op('(')) => {
+                self.cursor += 1;
+                let value = self.parse_sum()?;
+                ensure!(self.check_op(')'), "Unclosed parenthesis in expression: {}", self.expr);
+                Ok(value)
+            }
+            _ => bail!("Unexpected end of expression: {}", self.expr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(src: &str) -> PdxVariables {
+        let block = PdxBlock::parse_file("test.txt", src.as_bytes()).unwrap();
+        let mut variables = PdxVariables::new();
+        variables.add_definitions(&block).unwrap();
+        variables
+    }
+
+    #[test]
+    fn truncates_like_the_game() {
+        // the game truncates every intermediate result to three decimal places.
+        let vars = PdxVariables::new();
+        assert_eq!(vars.eval("1 / 3").unwrap(), 0.333);
+        assert_eq!(vars.eval("2 / 3").unwrap(), 0.666);
+        assert_eq!(vars.eval("-1 / 3").unwrap(), -0.333);
+        assert_eq!(vars.eval("10 / 3 * 3").unwrap(), 9.999);
+        assert_eq!(vars.eval("0.3").unwrap(), 0.3);
+        assert_eq!(vars.eval("0.1 + 0.2").unwrap(), 0.3);
+        assert_eq!(vars.eval("1.2345").unwrap(), 1.234);
+        assert_eq!(vars.eval("7 % 3").unwrap(), 1.0);
+        assert_eq!(vars.eval("(1 + 2) * -2").unwrap(), -6.0);
+    }
+
+    #[test]
+    fn truncates_variables() {
+        let vars = variables("@a = 1.0005");
+        assert_eq!(vars.eval("a * 2").unwrap(), 2.0);
+        assert_eq!(vars.eval("@a * 2").unwrap(), 2.0);
+    }
+
+    #[test]
+    fn evaluates_both_expression_forms() {
+        let vars = variables(
+            "@tier3cost = 250\n\
+             @plain = @[ tier3cost * 1.5 ]\n\
+             @escaped = @\\[ tier3cost / 3 ]\n",
+        );
+        assert_eq!(vars.get("plain"), Some(&PdxRelationValue::Numeric(375.0)));
+        assert_eq!(vars.get("escaped"), Some(&PdxRelationValue::Numeric(83.333)));
+    }
+
+    #[test]
+    fn parses_expression_values() {
+        let block = PdxBlock::parse_file("test.txt", b"a = @[ 1 + 2 ] b = @\\[ x * 2 ]").unwrap();
+        let values: Vec<_> = block
+            .contents
+            .iter()
+            .map(|x| match x {
+                PdxBlockContent::Relation(rel) => rel.value.clone(),
+                _ => panic!("expected a relation"),
+            })
+            .collect();
+        assert_eq!(values, vec![
+            PdxRelationValue::VariableExpr(" 1 + 2 ".into()),
+            PdxRelationValue::VariableExpr(" x * 2 ".into()),
+        ]);
+    }
+
+    #[test]
+    fn resolves_local_variables() {
+        let global = Arc::new(variables("@global = 2"));
+        let mut local = PdxVariables::with_parent(global);
+        let block = PdxBlock::parse_file("test.txt", b"@local = 3").unwrap();
+        local.add_definitions(&block).unwrap();
+
+        let resolve = |value| local.resolve_local(&value).unwrap();
+        assert_eq!(
+            resolve(PdxRelationValue::Variable("local".into())),
+            Some(PdxRelationValue::Numeric(3.0))
+        );
+        assert_eq!(resolve(PdxRelationValue::Variable("global".into())), None);
+        assert_eq!(
+            resolve(PdxRelationValue::VariableExpr("local * global".into())),
+            Some(PdxRelationValue::Numeric(6.0))
+        );
+        assert_eq!(resolve(PdxRelationValue::VariableExpr("@global * 2".into())), None);
+        assert_eq!(resolve(PdxRelationValue::String("local".into())), None);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let vars = PdxVariables::new();
+        assert!(vars.eval("1 / 0").is_err());
+        assert!(vars.eval("missing + 1").is_err());
+        assert!(vars.eval("(1 + 2").is_err());
+        assert!(vars.eval("1 +").is_err());
+    }
+}
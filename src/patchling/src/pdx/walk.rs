@@ -1 +1,329 @@
+use crate::pdx::{PdxBlock, PdxBlockContent, PdxRelation, PdxRelationValue};
+use std::sync::Arc;
 
+/// Controls how a walk continues after visiting a node.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum WalkControl {
+    /// Continues into the children of this node.
+    Continue,
+    /// Skips the children of this node, continuing with its next sibling.
+    SkipChildren,
+    /// Stops the walk entirely.
+    Break,
+}
+
+/// A visitor over a PDX tree.
+///
+/// Every method is passed the path of the node being visited, which is the chain of tags from
+/// the root of the walk. For relations and bare strings, this is the path of the block that
+/// contains them. For values and blocks, this includes the tag of the relation they belong to.
+///
+/// Nodes are borrowed for the lifetime of the tree, so a visitor may keep references to them.
+pub trait Visitor<'a> {
+    /// Called before the members of a block are walked.
+    fn visit_block(&mut self, _path: &[Arc<str>], _block: &'a PdxBlock) -> WalkControl {
+        WalkControl::Continue
+    }
+    /// Called after every member of a block has been walked, unless its children were skipped.
+    fn leave_block(&mut self, _path: &[Arc<str>], _block: &'a PdxBlock) {}
+    /// Called for each relation in a block, before its value is visited.
+    fn visit_relation(&mut self, _path: &[Arc<str>], _rel: &'a PdxRelation) -> WalkControl {
+        WalkControl::Continue
+    }
+    /// Called for the value of each relation, before the block it contains is walked, if any.
+    fn visit_value(&mut self, _path: &[Arc<str>], _value: &'a PdxRelationValue) -> WalkControl {
+        WalkControl::Continue
+    }
+    /// Called for each bare string in a block. Strings have no children to skip.
+    fn visit_string(&mut self, _path: &[Arc<str>], _str: &'a Arc<str>) -> WalkControl {
+        WalkControl::Continue
+    }
+}
+
+/// A visitor that may modify a PDX tree as it is walked.
+///
+/// Paths are tracked the same way as in [`Visitor`]. Children are walked after their parent is
+/// visited, so a visitor that replaces a node will walk the new node's children.
+pub trait VisitorMut {
+    /// Called before the members of a block are walked.
+    fn visit_block(&mut self, _path: &[Arc<str>], _block: &mut PdxBlock) -> WalkControl {
+        WalkControl::Continue
+    }
+    /// Called after every member of a block has been walked, unless its children were skipped.
+    fn leave_block(&mut self, _path: &[Arc<str>], _block: &mut PdxBlock) {}
+    /// Called for each relation in a block, before its value is visited. The path passed to its
+    /// children uses the tag the relation has after this returns.
+    fn visit_relation(&mut self, _path: &[Arc<str>], _rel: &mut PdxRelation) -> WalkControl {
+        WalkControl::Continue
+    }
+    /// Called for the value of each relation, before the block it contains is walked, if any.
+    fn visit_value(&mut self, _path: &[Arc<str>], _value: &mut PdxRelationValue) -> WalkControl {
+        WalkControl::Continue
+    }
+    /// Called for each bare string in a block. Strings have no children to skip.
+    fn visit_string(&mut self, _path: &[Arc<str>], _str: &mut Arc<str>) -> WalkControl {
+        WalkControl::Continue
+    }
+}
+
+impl PdxBlock {
+    /// Walks this block and everything in it. `leave_block` is not called for blocks that are
+    /// being walked when the walk is stopped.
+    pub fn walk<'a>(&'a self, visitor: &mut impl Visitor<'a>) {
+        walk_block(visitor, &mut Vec::new(), self);
+    }
+
+    /// Walks this block and everything in it, allowing the visitor to modify it.
+    pub fn walk_mut(&mut self, visitor: &mut impl VisitorMut) {
+        walk_block_mut(visitor, &mut Vec::new(), self);
+    }
+}
+
+// each of these functions returns `false` if the walk has been stopped.
+
+fn walk_block<'a>(
+    visitor: &mut impl Visitor<'a>,
+    path: &mut Vec<Arc<str>>,
+    block: &'a PdxBlock,
+) -> bool {
+    match visitor.visit_block(path, block) {
+        WalkControl::Continue => {}
+        WalkControl::SkipChildren => return true,
+        WalkControl::Break => return false,
+    }
+    for content in &block.contents {
+        let continued = match content {
+            PdxBlockContent::Relation(rel) => walk_relation(visitor, path, rel),
+            PdxBlockContent::String(str) => visitor.visit_string(path, str) != WalkControl::Break,
+        };
+        if !continued {
+            return false;
+        }
+    }
+    visitor.leave_block(path, block);
+    true
+}
+
+fn walk_relation<'a>(
+    visitor: &mut impl Visitor<'a>,
+    path: &mut Vec<Arc<str>>,
+    rel: &'a PdxRelation,
+) -> bool {
+    match visitor.visit_relation(path, rel) {
+        WalkControl::Continue => {}
+        WalkControl::SkipChildren => return true,
+        WalkControl::Break => return false,
+    }
+    path.push(rel.tag.clone());
+    let continued = match visitor.visit_value(path, &rel.value) {
+        WalkControl::Continue => match &rel.value {
+            PdxRelationValue::Block(block) => walk_block(visitor, path, block),
+            _ => true,
+        },
+        WalkControl::SkipChildren => true,
+        WalkControl::Break => false,
+    };
+    path.pop();
+    continued
+}
+
+fn walk_block_mut(
+    visitor: &mut impl VisitorMut,
+    path: &mut Vec<Arc<str>>,
+    block: &mut PdxBlock,
+) -> bool {
+    match visitor.visit_block(path, block) {
+        WalkControl::Continue => {}
+        WalkControl::SkipChildren => return true,
+        WalkControl::Break => return false,
+    }
+    for content in &mut block.contents {
+        let continued = match content {
+            PdxBlockContent::Relation(rel) => walk_relation_mut(visitor, path, rel),
+            PdxBlockContent::String(str) => visitor.visit_string(path, str) != WalkControl::Break,
+        };
+        if !continued {
+            return false;
+        }
+    }
+    visitor.leave_block(path, block);
+    true
+}
+
+fn walk_relation_mut(
+    visitor: &mut impl VisitorMut,
+    path: &mut Vec<Arc<str>>,
+    rel: &mut PdxRelation,
+) -> bool {
+    match visitor.visit_relation(path, rel) {
+        WalkControl::Continue => {}
+        WalkControl::SkipChildren => return true,
+        WalkControl::Break => return false,
+    }
+    path.push(rel.tag.clone());
+    let continued = match visitor.visit_value(path, &mut rel.value) {
+        WalkControl::Continue => match &mut rel.value {
+            PdxRelationValue::Block(block) => walk_block_mut(visitor, path, block),
+            _ => true,
+        },
+        WalkControl::SkipChildren => true,
+        WalkControl::Break => false,
+    };
+    path.pop();
+    continued
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(src: &str) -> PdxBlock {
+        PdxBlock::parse_file("test.txt", src.as_bytes()).unwrap()
+    }
+
+    /// Records every call made during a walk, and controls the walk by the tags it sees.
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>,
+        skip: Option<&'static str>,
+        stop: Option<&'static str>,
+    }
+    impl Recorder {
+        fn control(&self, tag: &str) -> WalkControl {
+            if self.stop == Some(tag) {
+                WalkControl::Break
+            } else if self.skip == Some(tag) {
+                WalkControl::SkipChildren
+            } else {
+                WalkControl::Continue
+            }
+        }
+        fn record(&mut self, kind: &str, path: &[Arc<str>], node: impl std::fmt::Display) {
+            self.events.push(format!("{} {} {}", kind, path.join("."), node).trim_end().into());
+        }
+    }
+    impl<'a> Visitor<'a> for Recorder {
+        fn visit_block(&mut self, path: &[Arc<str>], _block: &'a PdxBlock) -> WalkControl {
+            self.record("block", path, "");
+            WalkControl::Continue
+        }
+        fn leave_block(&mut self, path: &[Arc<str>], _block: &'a PdxBlock) {
+            self.record("leave", path, "");
+        }
+        fn visit_relation(&mut self, path: &[Arc<str>], rel: &'a PdxRelation) -> WalkControl {
+            self.record("relation", path, &rel.tag);
+            self.control(&rel.tag)
+        }
+        fn visit_value(&mut self, path: &[Arc<str>], value: &'a PdxRelationValue) -> WalkControl {
+            if !matches!(value, PdxRelationValue::Block(_)) {
+                self.record("value", path, value);
+            }
+            WalkControl::Continue
+        }
+        fn visit_string(&mut self, path: &[Arc<str>], str: &'a Arc<str>) -> WalkControl {
+            self.record("string", path, str);
+            self.control(str)
+        }
+    }
+
+    fn walk(src: &str, skip: Option<&'static str>, stop: Option<&'static str>) -> Vec<String> {
+        let mut recorder = Recorder { skip, stop, ..Recorder::default() };
+        parse(src).walk(&mut recorder);
+        recorder.events
+    }
+
+    #[test]
+    fn walks_in_order_with_paths() {
+        assert_eq!(walk("a = 1 b = { c = { d } e = x }", None, None), vec![
+            "block",
+            "relation  a",
+            "value a 1",
+            "relation  b",
+            "block b",
+            "relation b c",
+            "block b.c",
+            "string b.c d",
+            "leave b.c",
+            "relation b e",
+            "value b.e x",
+            "leave b",
+            "leave",
+        ]);
+    }
+
+    #[test]
+    fn skips_children() {
+        assert_eq!(walk("a = { b = c } d = e", Some("a"), None), vec![
+            "block",
+            "relation  a",
+            "relation  d",
+            "value d e",
+            "leave",
+        ]);
+    }
+
+    #[test]
+    fn stops_early() {
+        // blocks being walked when the walk stops are not left.
+        assert_eq!(walk("a = { b = { x y } c = d } e = f", None, Some("x")), vec![
+            "block",
+            "relation  a",
+            "block a",
+            "relation a b",
+            "block a.b",
+            "string a.b x",
+        ]);
+    }
+
+    #[test]
+    fn collects_references() {
+        struct Collector<'a>(Vec<&'a PdxRelation>);
+        impl<'a> Visitor<'a> for Collector<'a> {
+            fn visit_relation(&mut self, _path: &[Arc<str>], rel: &'a PdxRelation) -> WalkControl {
+                if rel.tag.ends_with("_mult") {
+                    self.0.push(rel);
+                }
+                WalkControl::Continue
+            }
+        }
+
+        let block = parse("a = { x_mult = 1 y_add = 2 } b = { c = { z_mult = 3 } }");
+        let mut collector = Collector(Vec::new());
+        block.walk(&mut collector);
+        let tags: Vec<_> = collector.0.iter().map(|x| &*x.tag).collect();
+        assert_eq!(tags, vec!["x_mult", "z_mult"]);
+    }
+
+    #[test]
+    fn modifies_the_tree() {
+        struct Renamer;
+        impl VisitorMut for Renamer {
+            fn visit_relation(&mut self, _path: &[Arc<str>], rel: &mut PdxRelation) -> WalkControl {
+                if &*rel.tag == "old" {
+                    rel.tag = "new".into();
+                }
+                WalkControl::Continue
+            }
+            fn visit_value(
+                &mut self,
+                path: &[Arc<str>],
+                value: &mut PdxRelationValue,
+            ) -> WalkControl {
+                // the path uses the tag after it was renamed.
+                if path == [Arc::from("new")] {
+                    *value = PdxRelationValue::Block(parse("old = 1"));
+                }
+                WalkControl::Continue
+            }
+            fn visit_string(&mut self, _path: &[Arc<str>], str: &mut Arc<str>) -> WalkControl {
+                *str = str.to_uppercase().into();
+                WalkControl::Continue
+            }
+        }
+
+        let mut block = parse("old = x a = { b c }");
+        block.walk_mut(&mut Renamer);
+        // the replacement value is walked too, so its own relation is renamed.
+        assert_eq!(block, parse("new = { new = 1 } a = { B C }"));
+    }
+}
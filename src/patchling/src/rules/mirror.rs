@@ -0,0 +1,136 @@
+use crate::lua::PROXY_KEY;
+use mlua::{prelude::LuaResult, Function, Lua, LuaSerdeExt, RegistryKey, Table, Value};
+use serde::*;
+use std::fmt;
+
+/// The kind of change a script made to a rule.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleChangeKind {
+    /// A value was assigned, or the whole rule was replaced.
+    Set,
+    /// A value was assigned `nil`, or the whole rule was deleted.
+    Delete,
+    /// A value was inserted into a list with `table.insert`.
+    Insert,
+    /// A value was removed from a list with `table.remove`.
+    Remove,
+    /// A list was reordered with `table.sort`.
+    Sort,
+}
+
+/// A change a script made to a rule.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RuleChange {
+    pub kind: RuleChangeKind,
+    /// The chain of keys in the rule's Lua mirror leading to the value that was changed. This is
+    /// empty if the whole rule was changed.
+    #[serde(default)]
+    pub path: Vec<String>,
+    /// The ID of the mod whose script made the change, or `None` if it was not made by a script
+    /// from a mod.
+    pub mod_name: Option<String>,
+    /// The module that made the change, relative to the mod's source or library directory.
+    pub module: Option<String>,
+    /// The line of the module's source that made the change.
+    pub line: Option<u32>,
+}
+impl RuleChange {
+    /// Returns the mod and module of the script that made this change.
+    pub fn script(&self) -> Option<(&str, &str)> {
+        Some((self.mod_name.as_deref()?, self.module.as_deref()?))
+    }
+}
+
+impl fmt::Display for RuleChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            RuleChangeKind::Set => "set",
+            RuleChangeKind::Delete => "deleted",
+            RuleChangeKind::Insert => "inserted",
+            RuleChangeKind::Remove => "removed",
+            RuleChangeKind::Sort => "sorted",
+        })
+    }
+}
+
+impl fmt::Display for RuleChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<8} ", self.kind)?;
+        if self.path.is_empty() {
+            f.write_str("rule")?;
+        } else {
+            f.write_str(&self.path.join("."))?;
+        }
+        if let Some((mod_name, module)) = self.script() {
+            write!(f, " (by {}:{}", mod_name, module)?;
+            if let Some(line) = self.line {
+                write!(f, ":{}", line)?;
+            }
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
+
+fn proxy_module(lua: &Lua) -> LuaResult<Table<'_>> {
+    lua.named_registry_value(PROXY_KEY)
+}
+
+/// Replaces every proxy in a Lua value with a copy of the table it wraps, so it can be read back
+/// into Rust or used as a new mirror.
+pub(in crate::rules) fn unwrap<'lua>(lua: &'lua Lua, value: Value<'lua>) -> LuaResult<Value<'lua>> {
+    proxy_module(lua)?.get::<_, Function<'_>>("unwrap")?.call(value)
+}
+
+/// The Lua mirror of a rule, and the proxy scripts access it through.
+#[derive(Debug)]
+pub(in crate::rules) struct LuaMirror {
+    table: RegistryKey,
+    proxy: RegistryKey,
+}
+impl LuaMirror {
+    /// Creates a mirror from a Lua value, which may contain proxies of other mirrors. Changes made
+    /// through the proxy are recorded into `changes`.
+    pub fn new<'lua>(lua: &'lua Lua, value: Value<'lua>, changes: &RegistryKey) -> LuaResult<Self> {
+        let module = proxy_module(lua)?;
+        let table = unwrap(lua, value)?;
+        let changes: Table<'_> = lua.registry_value(changes)?;
+        let proxy: Value<'_> =
+            module.get::<_, Function<'_>>("wrap_mirror")?.call((table.clone(), changes))?;
+        Ok(LuaMirror {
+            table: lua.create_registry_value(table)?,
+            proxy: lua.create_registry_value(proxy)?,
+        })
+    }
+
+    /// Returns the mirror itself, for reading it back into Rust.
+    pub fn table<'lua>(&self, lua: &'lua Lua) -> LuaResult<Value<'lua>> {
+        lua.registry_value(&self.table)
+    }
+
+    /// Returns the proxy that is given to scripts.
+    pub fn proxy<'lua>(&self, lua: &'lua Lua) -> LuaResult<Value<'lua>> {
+        lua.registry_value(&self.proxy)
+    }
+}
+
+/// Records a change to a whole rule made by the script calling into Rust.
+pub(in crate::rules) fn record_change(
+    lua: &Lua,
+    changes: &RegistryKey,
+    kind: RuleChangeKind,
+) -> LuaResult<()> {
+    let changes: Table<'_> = lua.registry_value(changes)?;
+    let record_change: Function<'_> = proxy_module(lua)?.get("record_change")?;
+    // level 1 is the Rust function calling `record_change`, and level 2 is the script.
+    record_change.call((changes, lua.to_value(&kind)?, 2))
+}
+
+/// Reads back the changes recorded for a rule.
+pub(in crate::rules) fn read_changes(
+    lua: &Lua,
+    changes: &RegistryKey,
+) -> LuaResult<Vec<RuleChange>> {
+    lua.from_value(lua.registry_value(changes)?)
+}
@@ -1,8 +1,20 @@
+mod mirror;
+mod output;
+mod report;
 mod resolve;
 mod rules_parser;
 
+pub use mirror::{RuleChange, RuleChangeKind};
+pub use report::{
+    ConflictReport, DefinitionStatus, FileConflict, RuleConflict, RuleDefinition, RuleHistoryEntry,
+};
+
 use crate::{
-    pdx::{PdxBlock, PdxRelation, PdxRelationType, PdxRelationValue},
+    pdx::{
+        PdxBlock, PdxInterner, PdxMergeConflict, PdxRelation, PdxRelationType, PdxRelationValue,
+        PdxSpan, PdxVariables,
+    },
+    rules::mirror::LuaMirror,
     Game,
 };
 use anyhow::*;
@@ -10,16 +22,48 @@ use indexmap::IndexMap;
 use mlua::{
     prelude::{LuaResult, LuaString},
     serde::LuaSerdeExt,
-    Lua, RegistryKey, UserData, UserDataMethods, Value,
+    AnyUserData, Lua, MultiValue, RegistryKey, Table, UserData, UserDataMethods, Value,
 };
 use serde::*;
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use twox_hash::RandomXxh3HashBuilder64;
 
+/// How the game resolves multiple definitions of the same rule in a data directory.
 #[derive(Serialize, Deserialize, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug)]
 #[serde(rename_all = "snake_case")]
 pub enum ResolverMode {
-    Simple,
+    /// "First in, only served". The first definition loaded is used, and later ones are ignored.
+    Fios,
+    /// "Last in, only served". Each definition completely replaces the ones loaded before it.
+    Lios,
+    /// Files are replaced as a whole by files with the same name. Each file is resolved as a
+    /// single rule named after the file, containing the file's contents.
+    FileReplace,
+    /// Works like [`ResolverMode::Lios`], except that mod definitions are merged against the
+    /// vanilla definition, so changes made by different mods are combined.
+    ///
+    /// The game never does this itself, so this mode is only used when a script asks for it.
+    Merge,
+    /// The contents of every definition of a rule are appended together.
+    Append,
+}
+
+/// How the rules in the files of a data directory are named.
+#[derive(Serialize, Deserialize, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleLayout {
+    /// Each top-level relation is a rule, named after its tag.
+    Tag,
+    /// Each top-level block is a rule, named after the value of its `id` member, as with events.
+    /// Other top-level relations, such as `namespace = foo`, are not rules.
+    Id,
+    /// Each top-level block is a container, such as `spriteTypes`, and each of its members is a
+    /// rule, named after the value of its `name` member.
+    Container,
 }
 
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug)]
@@ -43,17 +87,61 @@ enum DefaultRuleType {
     RuleEquals,
 }
 
+/// The origin of rules that were created by scripts rather than loaded from a data root.
+const SCRIPT_ORIGIN: u32 = u32::MAX;
+/// The name reported as the origin of rules that were last set by a script.
+const SCRIPT_ORIGIN_NAME: &str = "<script>";
+
+/// A file rules were loaded from.
+#[derive(Debug)]
+struct RuleFile {
+    origin_mod: u32,
+    is_mod: bool,
+    path: PathBuf,
+    /// The variables in scope in the file, including the global scripted variables.
+    variables: PdxVariables,
+}
+
+/// A definition of a rule loaded from a data root.
+#[derive(Debug)]
+struct RuleSource {
+    span: Option<PdxSpan>,
+    /// The tag of the container block the definition was in, for [`RuleLayout::Container`].
+    container: Option<Arc<str>>,
+    file: Arc<RuleFile>,
+}
+
+/// A file that replaced files with the same relative path in earlier data roots.
+#[derive(Debug)]
+struct OverwrittenFile {
+    file_name: String,
+    origin_mod: u32,
+    overwritten: Vec<u32>,
+}
+
 #[derive(Debug)]
 struct RuleInfo {
     origin_mod: u32,
+    /// Every definition of the rule that was loaded, in load order.
+    sources: Vec<RuleSource>,
+    deleted: bool,
+    /// The vanilla definition of the rule, which mod definitions are merged against.
+    base: Option<PdxRelation>,
     original: Option<PdxRelation>,
-    lua_mirror: Option<RegistryKey>,
+    conflicts: Vec<PdxMergeConflict>,
+    /// Whether the resolved definition differs from the one the game loads, so it must be
+    /// written to the output even if no script changes it.
+    patched: bool,
+    lua_mirror: Option<LuaMirror>,
+    /// The changes scripts made to the rule, as a Lua table of [`RuleChange`]s.
+    lua_changes: Option<RegistryKey>,
 }
 impl RuleInfo {
     fn get_lua_mirror<'lua>(
         &mut self,
         rule_name: &str,
         default: &DefaultRuleType,
+        interner: &PdxInterner,
         lua: &'lua Lua,
     ) -> LuaResult<Value<'lua>> {
         if self.lua_mirror.is_none() {
@@ -61,47 +149,170 @@ impl RuleInfo {
                 Some(relation) => lua.to_value(relation)?,
                 None => match default {
                     DefaultRuleType::RuleEquals => lua.to_value(&PdxRelation {
-                        tag: rule_name.into(), // TODO: Pass an interner here.
+                        tag: interner.intern(rule_name),
                         relation: PdxRelationType::Normal,
-                        value: PdxRelationValue::Block(PdxBlock { contents: Vec::new() }),
+                        value: PdxRelationValue::Block(PdxBlock::new(Vec::new())),
+                        span: None,
                     })?,
                 },
             };
 
-            let key = lua.create_registry_value(new_value)?;
-            self.lua_mirror = Some(key);
+            self.init_changes(lua)?;
+            let changes = self.lua_changes.as_ref().unwrap();
+            self.lua_mirror = Some(LuaMirror::new(lua, new_value, changes)?);
+        }
+        self.lua_mirror.as_ref().unwrap().proxy(lua)
+    }
+
+    fn init_changes(&mut self, lua: &Lua) -> LuaResult<()> {
+        if self.lua_changes.is_none() {
+            self.lua_changes = Some(lua.create_registry_value(lua.create_table()?)?);
+        }
+        Ok(())
+    }
+
+    /// Returns whether any script has changed this rule.
+    fn is_dirty(&self, lua: &Lua) -> LuaResult<bool> {
+        match &self.lua_changes {
+            Some(changes) => Ok(lua.registry_value::<Table<'_>>(changes)?.raw_len() > 0),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns the changes scripts have made to this rule, in the order they were made.
+    fn changes(&self, lua: &Lua) -> LuaResult<Vec<RuleChange>> {
+        match &self.lua_changes {
+            Some(changes) => mirror::read_changes(lua, changes),
+            None => Ok(Vec::new()),
         }
-        lua.registry_value(self.lua_mirror.as_ref().unwrap())
     }
 }
 
 #[derive(Debug)]
 pub struct ResolvedRules {
     default: DefaultRuleType,
+    mode: ResolverMode,
+    layout: RuleLayout,
     path: String,
-    map: IndexMap<String, RuleInfo, RandomXxh3HashBuilder64>,
+    /// The names of the data roots rules were loaded from, indexed by `origin_mod`.
+    origins: Vec<Arc<str>>,
+    /// Vanilla definitions from files that were replaced by mods, used as merge bases.
+    shadowed_bases: HashMap<Arc<str>, PdxRelation>,
+    overwritten_files: Vec<OverwrittenFile>,
+    /// The global scripted variables, used for rules that were not loaded from a file.
+    variables: Arc<PdxVariables>,
+    /// The rules in load order, keyed by their interned names.
+    map: IndexMap<Arc<str>, RuleInfo, RandomXxh3HashBuilder64>,
+    interner: Arc<PdxInterner>,
     initialized: bool,
 }
 impl ResolvedRules {
-    fn new(character: DefaultRuleType, path: &str) -> Self {
+    fn new(
+        character: DefaultRuleType,
+        mode: ResolverMode,
+        layout: RuleLayout,
+        path: &str,
+        roots: &[DataRoot],
+        variables: Arc<PdxVariables>,
+        interner: Arc<PdxInterner>,
+    ) -> Self {
         ResolvedRules {
             default: character,
+            mode,
+            layout,
             path: path.to_string(),
+            origins: roots.iter().map(|x| x.name.clone()).collect(),
+            shadowed_bases: HashMap::new(),
+            overwritten_files: Vec::new(),
+            variables,
             map: Default::default(),
+            interner,
             initialized: false,
         }
     }
 
-    fn add_rule_from_sources(&mut self, origin_mod: u32, name: &str, rule: PdxRelation) {
+    fn add_shadowed_base(&mut self, name: Arc<str>, rule: PdxRelation) {
         assert!(!self.initialized, "Cannot add rule from sources after initialization.");
-        if self.map.contains_key(name) {
-            self.map.insert(name.to_string(), RuleInfo {
-                origin_mod,
-                original: Some(rule),
-                lua_mirror: None,
-            });
-        } else {
-            trace!("Ignoring rule {}. (Already defined.)", name);
+        self.shadowed_bases.insert(name, rule);
+    }
+
+    fn add_overwritten_file(&mut self, file_name: &str, origin_mod: u32, overwritten: Vec<u32>) {
+        assert!(!self.initialized, "Cannot add files after initialization.");
+        self.overwritten_files.push(OverwrittenFile {
+            file_name: file_name.to_string(),
+            origin_mod,
+            overwritten,
+        });
+    }
+
+    fn add_rule_from_sources(
+        &mut self,
+        file: &Arc<RuleFile>,
+        container: Option<Arc<str>>,
+        name: Arc<str>,
+        rule: PdxRelation,
+    ) {
+        assert!(!self.initialized, "Cannot add rule from sources after initialization.");
+        let (origin_mod, is_mod) = (file.origin_mod, file.is_mod);
+        let source = RuleSource { span: rule.span.clone(), container, file: file.clone() };
+        match self.map.get_mut(&name) {
+            None => {
+                let base =
+                    if is_mod { self.shadowed_bases.remove(&name) } else { Some(rule.clone()) };
+                self.map.insert(name, RuleInfo {
+                    origin_mod,
+                    sources: vec![source],
+                    deleted: false,
+                    base,
+                    original: Some(rule),
+                    conflicts: Vec::new(),
+                    patched: false,
+                    lua_mirror: None,
+                    lua_changes: None,
+                });
+            }
+            Some(info) if self.mode == ResolverMode::Fios => {
+                info.sources.push(source);
+                ignore_rule(&name, &rule);
+            }
+            Some(info) => {
+                info.origin_mod = origin_mod;
+                info.sources.push(source);
+                match self.mode {
+                    ResolverMode::Fios => unreachable!(),
+                    ResolverMode::Lios | ResolverMode::FileReplace => info.original = Some(rule),
+                    ResolverMode::Merge if !is_mod => {
+                        // vanilla definitions replace each other, as in `Lios`.
+                        info.base = Some(rule.clone());
+                        info.original = Some(rule);
+                    }
+                    ResolverMode::Merge => {
+                        // each mod's definition is merged against vanilla, on top of the
+                        // definitions from the mods loaded before it.
+                        let accumulated = info.original.as_ref().expect("Rule has no definition?");
+                        let merge = PdxRelation::merge3(info.base.as_ref(), accumulated, &rule);
+                        for conflict in &merge.conflicts {
+                            warn!("Conflict merging rule {}: {}", name, conflict);
+                        }
+                        info.patched = merge.merged != rule;
+                        info.original = Some(merge.merged);
+                        info.conflicts.extend(merge.conflicts);
+                    }
+                    ResolverMode::Append => {
+                        let accumulated = info.original.as_mut().expect("Rule has no definition?");
+                        match (&mut accumulated.value, rule.value) {
+                            (PdxRelationValue::Block(old), PdxRelationValue::Block(new)) => {
+                                old.contents.extend(new.contents)
+                            }
+                            (_, value) => {
+                                // only blocks can be appended to, so anything else is replaced.
+                                accumulated.value = value;
+                                accumulated.span = rule.span;
+                            }
+                        }
+                    }
+                }
+            }
         }
     }
 
@@ -113,18 +324,173 @@ impl ResolvedRules {
     fn get_rule(&mut self, origin_mod: u32, name: &str) -> &mut RuleInfo {
         assert!(self.initialized, "Cannot get rules in an uninitialized rules set.");
         if !self.map.contains_key(name) {
-            self.map.insert(name.to_string(), RuleInfo {
+            self.map.insert(self.interner.intern(name), RuleInfo {
                 origin_mod,
+                sources: Vec::new(),
+                deleted: false,
+                base: None,
                 original: None,
+                conflicts: Vec::new(),
+                patched: false,
                 lua_mirror: None,
+                lua_changes: None,
             });
         }
         self.map.get_mut(name).unwrap()
     }
+
+    /// Returns the changes scripts have made to every rule that was modified, in load order.
+    pub fn modified_rules(&self, lua: &Lua) -> Result<Vec<(String, Vec<RuleChange>)>> {
+        let mut modified = Vec::new();
+        for (name, info) in &self.map {
+            if info.is_dirty(lua)? {
+                modified.push((name.to_string(), info.changes(lua)?));
+            }
+        }
+        Ok(modified)
+    }
+
+    /// Returns the definition of a rule the game uses, or the last one if they are combined.
+    fn used_source<'a>(&self, info: &'a RuleInfo) -> Option<&'a RuleSource> {
+        match self.mode {
+            ResolverMode::Fios => info.sources.first(),
+            _ => info.sources.last(),
+        }
+    }
+
+    /// Returns the variables in scope for a rule, from the file the definition the game uses was
+    /// loaded from.
+    fn rule_variables(&self, name: &str) -> &PdxVariables {
+        match self.map.get(name).and_then(|info| self.used_source(info)) {
+            Some(source) => &source.file.variables,
+            None => &self.variables,
+        }
+    }
+
+    fn get_existing_rule(&mut self, name: &str) -> Option<&mut RuleInfo> {
+        assert!(self.initialized, "Cannot get rules in an uninitialized rules set.");
+        self.map.get_mut(name).filter(|x| !x.deleted)
+    }
+
+    fn get_existing_mirror<'lua>(&mut self, lua: &'lua Lua, name: &str) -> LuaResult<Value<'lua>> {
+        let default = &self.default;
+        match self.map.get_mut(name).filter(|x| !x.deleted) {
+            Some(info) => info.get_lua_mirror(name, default, &self.interner, lua),
+            None => Ok(Value::Nil),
+        }
+    }
+}
+
+fn ignore_rule(name: &str, rule: &PdxRelation) {
+    match &rule.span {
+        Some(span) => trace!("Ignoring rule {} at {}. (Already defined.)", name, span),
+        None => trace!("Ignoring rule {}. (Already defined.)", name),
+    }
 }
+
 impl UserData for ResolvedRules {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-
+        methods.add_method_mut("get", |lua, this, name: LuaString<'_>| {
+            this.get_existing_mirror(lua, name.to_str()?)
+        });
+        methods.add_method_mut("set", |lua, this, (name, rule): (LuaString<'_>, Value<'_>)| {
+            // check that the value is actually a relation before changing anything.
+            let rule = mirror::unwrap(lua, rule)?;
+            let _: PdxRelation = lua.from_value(rule.clone())?;
+            let info = this.get_rule(SCRIPT_ORIGIN, name.to_str()?);
+            info.init_changes(lua)?;
+            let changes = info.lua_changes.as_ref().unwrap();
+            let mirror = LuaMirror::new(lua, rule, changes)?;
+            mirror::record_change(lua, changes, RuleChangeKind::Set)?;
+            info.origin_mod = SCRIPT_ORIGIN;
+            info.deleted = false;
+            info.lua_mirror = Some(mirror);
+            Ok(())
+        });
+        methods.add_method_mut("delete", |lua, this, name: LuaString<'_>| {
+            if let Some(info) = this.get_existing_rule(name.to_str()?) {
+                info.init_changes(lua)?;
+                mirror::record_change(
+                    lua,
+                    info.lua_changes.as_ref().unwrap(),
+                    RuleChangeKind::Delete,
+                )?;
+                info.deleted = true;
+                info.lua_mirror = None;
+            }
+            Ok(())
+        });
+        methods.add_method_mut("has", |_, this, name: LuaString<'_>| {
+            Ok(this.get_existing_rule(name.to_str()?).is_some())
+        });
+        methods.add_method("is_modified", |lua, this, name: LuaString<'_>| {
+            match this.map.get(name.to_str()?) {
+                Some(info) => info.is_dirty(lua),
+                None => Ok(false),
+            }
+        });
+        // Returns the list of changes scripts have made to a rule.
+        methods.add_method("changes", |lua, this, name: LuaString<'_>| {
+            match this.map.get(name.to_str()?) {
+                Some(info) => lua.to_value(&info.changes(lua)?),
+                None => lua.to_value(&Vec::<RuleChange>::new()),
+            }
+        });
+        // Returns the name of the data root a rule was last defined in, or `<script>` if a script
+        // set it since.
+        methods.add_method_mut("origin", |_, this, name: LuaString<'_>| {
+            let origin_mod = match this.get_existing_rule(name.to_str()?) {
+                Some(info) => info.origin_mod,
+                None => return Ok(None),
+            };
+            match origin_mod {
+                SCRIPT_ORIGIN => Ok(Some(SCRIPT_ORIGIN_NAME.to_string())),
+                _ => Ok(this.origins.get(origin_mod as usize).map(|x| x.to_string())),
+            }
+        });
+        // Returns the definitions a rule was loaded from and the changes scripts made to it.
+        methods.add_method("history", |lua, this, name: LuaString<'_>| {
+            match this.history(name.to_str()?, Some(lua))? {
+                Some(history) => lua.to_value(&history),
+                None => Ok(Value::Nil),
+            }
+        });
+        // Returns the value of a variable in the scope of the file a rule was defined in.
+        methods.add_method("get_variable", |lua, this, args: (LuaString<'_>, LuaString<'_>)| {
+            let (rule, name) = args;
+            let name = name.to_str()?;
+            let name = name.strip_prefix('@').unwrap_or(name);
+            match this.rule_variables(rule.to_str()?).get(name) {
+                Some(value) => lua.to_value(value),
+                None => Ok(Value::Nil),
+            }
+        });
+        methods.add_method(
+            "eval_variable_expr",
+            |_, this, (rule, expr): (LuaString<'_>, LuaString<'_>)| {
+                let variables = this.rule_variables(rule.to_str()?);
+                variables.eval(expr.to_str()?).map_err(mlua::Error::external)
+            },
+        );
+        // Iterates over the names and values of all rules, in the order they were loaded.
+        methods.add_function("pairs", |lua, this: AnyUserData<'_>| {
+            let names: Vec<Arc<str>> =
+                this.borrow::<ResolvedRules>()?.map.keys().cloned().collect();
+            let mut names = names.into_iter();
+            let this = lua.create_registry_value(this)?;
+            lua.create_function_mut(move |lua, _: MultiValue<'_>| {
+                let this: AnyUserData<'_> = lua.registry_value(&this)?;
+                let mut this = this.borrow_mut::<ResolvedRules>()?;
+                // rules deleted during iteration are skipped.
+                for name in &mut names {
+                    let value = this.get_existing_mirror(lua, &name)?;
+                    if value != Value::Nil {
+                        return Ok((Some(lua.create_string(name.as_bytes())?), value));
+                    }
+                }
+                Ok((None, Value::Nil))
+            })
+        });
     }
 }
 
@@ -132,26 +498,164 @@ impl UserData for ResolvedRules {
 pub struct RulesManager {
     game: Game,
     data_roots: Vec<DataRoot>,
-    resolvers: HashMap<(String, String), RegistryKey>,
+    /// The resolvers loaded by scripts, by directory and extension, along with the mode each was
+    /// loaded with.
+    resolvers: HashMap<(String, String), (ResolverMode, RegistryKey)>,
+    variables: Option<Arc<PdxVariables>>,
+    interner: Arc<PdxInterner>,
+    history_comments: bool,
 }
 impl RulesManager {
     pub fn new(game: Game) -> RulesManager {
-        RulesManager { game, data_roots: Vec::new(), resolvers: HashMap::new() }
+        RulesManager {
+            game,
+            data_roots: Vec::new(),
+            resolvers: HashMap::new(),
+            variables: None,
+            interner: Arc::new(PdxInterner::new()),
+            history_comments: false,
+        }
+    }
+
+    /// Returns the interner shared by every rule loaded through this manager.
+    pub fn interner(&self) -> &Arc<PdxInterner> {
+        &self.interner
+    }
+
+    /// Sets whether rules written to the output are preceded by comments explaining where they
+    /// came from.
+    pub fn set_history_comments(&mut self, history_comments: bool) {
+        self.history_comments = history_comments;
     }
 
     pub fn add_data_root(&mut self, root: DataRoot) {
         self.data_roots.push(root);
+        self.resolvers.clear();
+        self.variables = None;
+    }
+
+    /// Returns the global scripted variables of the game and all loaded mods.
+    pub fn scripted_variables(&mut self) -> Result<Arc<PdxVariables>> {
+        if self.variables.is_none() {
+            let variables = resolve::load_scripted_variables(&self.data_roots, &self.interner)?;
+            self.variables = Some(Arc::new(variables));
+        }
+        Ok(self.variables.clone().unwrap())
+    }
+
+    /// Writes the rules that were changed by scripts or merged from several mods to an output
+    /// mod directory.
+    pub fn write_output(&self, lua: &Lua, out_dir: &Path) -> Result<()> {
+        for ((_, extension), (_, resolver)) in &self.resolvers {
+            let resolver: AnyUserData<'_> = lua.registry_value(resolver)?;
+            let rules = resolver.borrow::<ResolvedRules>()?;
+            rules.write_output(lua, self.game, extension, out_dir, self.history_comments)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the definitions of a rule in a data directory, in load order, and whether the game
+    /// uses each of them.
+    pub fn explain(
+        &mut self,
+        directory: &str,
+        extension: &str,
+        name: &str,
+    ) -> Result<Option<Vec<RuleHistoryEntry>>> {
+        let mode = self.game.resolver_mode(directory);
+        let layout = self.game.rule_layout(directory);
+        let variables = self.scripted_variables()?;
+        let rules = resolve::resolve_rules(
+            &self.data_roots,
+            mode,
+            layout,
+            directory,
+            extension,
+            &variables,
+            &self.interner,
+        )?;
+        Ok(rules.history(name, None)?)
+    }
+
+    /// Reports the rules and files in a data directory that are defined by more than one data
+    /// root, and which of their definitions the game uses.
+    pub fn conflict_report(&mut self, directory: &str, extension: &str) -> Result<ConflictReport> {
+        let mode = self.game.resolver_mode(directory);
+        let layout = self.game.rule_layout(directory);
+        let variables = self.scripted_variables()?;
+        let rules = resolve::resolve_rules(
+            &self.data_roots,
+            mode,
+            layout,
+            directory,
+            extension,
+            &variables,
+            &self.interner,
+        )?;
+        Ok(rules.conflict_report())
     }
 }
 impl UserData for RulesManager {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
         methods.add_method("get_game", |lua, this, _: ()| Ok(lua.to_value(&this.game)));
-        methods.add_method(
+        methods.add_method_mut("get_variable", |lua, this, name: LuaString<'_>| {
+            let variables = this.scripted_variables().map_err(mlua::Error::external)?;
+            let name = name.to_str()?;
+            let name = name.strip_prefix('@').unwrap_or(name);
+            match variables.get(name) {
+                Some(value) => lua.to_value(value),
+                None => Ok(Value::Nil),
+            }
+        });
+        methods.add_method_mut("eval_variable_expr", |_, this, expr: LuaString<'_>| {
+            let variables = this.scripted_variables().map_err(mlua::Error::external)?;
+            variables.eval(expr.to_str()?).map_err(mlua::Error::external)
+        });
+        methods.add_method_mut(
             "get_resolver",
             |lua, this, args: (LuaString<'_>, Option<LuaString<'_>>, Option<Value<'_>>)| {
                 let (path, extension, resolver_mode) = args;
-                // TODO: Build resolvers
-                Ok(0)
+                let path = path.to_str()?.to_string();
+                let extension = match extension {
+                    Some(extension) => extension.to_str()?.to_string(),
+                    None => ".txt".to_string(),
+                };
+                let resolver_mode = match resolver_mode {
+                    Some(mode) => lua.from_value(mode)?,
+                    None => this.game.resolver_mode(&path),
+                };
+
+                let key = (path, extension);
+                if let Some((mode, resolver)) = this.resolvers.get(&key) {
+                    // a directory can only be written to the output once, so it cannot be
+                    // resolved in two different ways.
+                    if *mode != resolver_mode {
+                        return Err(mlua::Error::external(anyhow!(
+                            "Rules in {} were already loaded with resolver mode {:?}, and cannot \
+                             be loaded with mode {:?}.",
+                            key.0,
+                            mode,
+                            resolver_mode,
+                        )));
+                    }
+                    return lua.registry_value::<Value<'_>>(resolver);
+                }
+                let variables = this.scripted_variables().map_err(mlua::Error::external)?;
+                let (path, extension) = (&key.0, &key.1);
+                let rules = resolve::resolve_rules(
+                    &this.data_roots,
+                    resolver_mode,
+                    this.game.rule_layout(path),
+                    path,
+                    extension,
+                    &variables,
+                    &this.interner,
+                )
+                .map_err(mlua::Error::external)?;
+                let resolver = Value::UserData(lua.create_userdata(rules)?);
+                let registry_key = lua.create_registry_value(resolver.clone())?;
+                this.resolvers.insert(key, (resolver_mode, registry_key));
+                Ok(resolver)
             },
         );
     }
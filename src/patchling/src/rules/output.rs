@@ -0,0 +1,340 @@
+use crate::{
+    pdx::{
+        PdxBlock, PdxBlockContent, PdxDiffChange, PdxRelation, PdxRelationValue, PdxSourceFile,
+        PdxVariables, VisitorMut, WalkControl,
+    },
+    rules::{ResolvedRules, ResolverMode, RuleLayout},
+    Game,
+};
+use anyhow::*;
+use mlua::{Lua, LuaSerdeExt};
+use std::{fs, path::Path, sync::Arc};
+
+/// The name of the file changed rules are written to in directories where the first definition
+/// of a rule wins. `!` sorts before any alphanumeric file name.
+const FIRST_FILE_NAME: &str = "!!!_patchling";
+/// The name of the file changed rules are written to in directories where the last definition
+/// of a rule wins.
+const LAST_FILE_NAME: &str = "zzz_patchling";
+
+/// Replaces references to the variables defined at the top of a rule's files with their values,
+/// as the rule is written to a file where those definitions do not exist.
+struct LocalVariables<'a> {
+    /// The scopes of the files the rule was defined in, with the one the game uses first.
+    scopes: Vec<&'a PdxVariables>,
+    error: Option<Error>,
+}
+impl VisitorMut for LocalVariables<'_> {
+    fn visit_value(&mut self, _path: &[Arc<str>], value: &mut PdxRelationValue) -> WalkControl {
+        for scope in &self.scopes {
+            match scope.resolve_local(value) {
+                Ok(Some(resolved)) => {
+                    *value = resolved;
+                    break;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    self.error = Some(e);
+                    return WalkControl::Break;
+                }
+            }
+        }
+        WalkControl::Continue
+    }
+}
+
+impl ResolvedRules {
+    /// Returns the current version of every rule that must be written to the output, in load
+    /// order.
+    fn changed_rules(&self, lua: &Lua) -> Result<Vec<(&str, Option<&PdxRelation>, PdxRelation)>> {
+        let mut changed = Vec::new();
+        for (name, info) in &self.map {
+            let mut scripts: Vec<(&str, &str)> = Vec::new();
+            let changes = info.changes(lua)?;
+            for script in changes.iter().filter_map(|x| x.script()) {
+                if !scripts.contains(&script) {
+                    scripts.push(script);
+                }
+            }
+            if scripts.len() > 1 {
+                let scripts: Vec<_> = scripts
+                    .iter()
+                    .map(|(mod_name, module)| format!("{}:{}", mod_name, module))
+                    .collect();
+                warn!(
+                    "Rule {} in {} was modified by multiple scripts: {}",
+                    name,
+                    self.path,
+                    scripts.join(", "),
+                );
+            }
+
+            if info.deleted {
+                if info.original.is_some() {
+                    warn!(
+                        "Rule {} in {} was deleted, but rules cannot be removed.",
+                        name, self.path
+                    );
+                }
+                continue;
+            }
+
+            let current = match &info.lua_mirror {
+                Some(mirror) if info.is_dirty(lua)? => {
+                    let mut relation: PdxRelation = lua
+                        .from_value(mirror.table(lua)?)
+                        .with_context(|| format!("Rule {} in {} is malformed", name, self.path))?;
+                    self.interner.intern_relation(&mut relation);
+                    Some(relation)
+                }
+                _ => None,
+            };
+            match current {
+                Some(current) if info.original.as_ref() != Some(&current) => {
+                    changed.push((&**name, info.original.as_ref(), current))
+                }
+                _ if info.patched => {
+                    let original = info.original.clone().expect("Rule has no definition?");
+                    changed.push((&**name, None, original))
+                }
+                _ => {}
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Returns comment lines explaining where a rule came from, to be written before it.
+    fn history_header(&self, lua: &Lua, name: &str, history_comments: bool) -> Result<String> {
+        let mut header = String::new();
+        if history_comments {
+            for entry in self.history(name, Some(lua))?.unwrap_or_default() {
+                header.push_str(&format!("# {}\n", entry));
+            }
+        }
+        Ok(header)
+    }
+
+    /// Replaces references to file-local variables in a rule with their values.
+    fn resolve_local_variables(&self, name: &str, block: &mut PdxBlock) -> Result<()> {
+        let mut scopes: Vec<_> = match self.map.get(name) {
+            Some(info) => info.sources.iter().map(|x| &x.file.variables).collect(),
+            None => Vec::new(),
+        };
+        if self.mode != ResolverMode::Fios {
+            scopes.reverse();
+        }
+        let mut visitor = LocalVariables { scopes, error: None };
+        block.walk_mut(&mut visitor);
+        match visitor.error {
+            Some(e) => Err(e.context(format!("Rule {} in {} is malformed", name, self.path))),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the tag of the container block a rule must be written in.
+    fn rule_container(&self, name: &str) -> Result<Arc<str>> {
+        let source = self.map.get(name).and_then(|info| self.used_source(info));
+        match source.and_then(|x| x.container.clone()) {
+            Some(container) => Ok(container),
+            None => bail!(
+                "Rule {} in {} was not loaded from a file, so the block it belongs in is unknown.",
+                name,
+                self.path,
+            ),
+        }
+    }
+
+    /// Renders a list of rules as the contents of a file.
+    fn render_rules(
+        &self,
+        lua: &Lua,
+        rules: Vec<(&str, PdxRelation)>,
+        history_comments: bool,
+    ) -> Result<String> {
+        let mut text = String::new();
+        if self.layout == RuleLayout::Id {
+            // event ids refer to a namespace, which must be declared in the file using them.
+            let mut namespaces = Vec::new();
+            for (name, _) in &rules {
+                if let Some((namespace, _)) = name.split_once('.') {
+                    if !namespaces.contains(&namespace) {
+                        namespaces.push(namespace);
+                    }
+                }
+            }
+            for namespace in namespaces {
+                text.push_str(&format!("namespace = {}\n", namespace));
+            }
+        }
+
+        let mut containers: Vec<(Arc<str>, String)> = Vec::new();
+        for (name, rule) in rules {
+            let header = self.history_header(lua, name, history_comments)?;
+            let mut block = PdxBlock::new(vec![PdxBlockContent::Relation(rule)]);
+            self.resolve_local_variables(name, &mut block)?;
+            if self.layout != RuleLayout::Container {
+                text.push_str(&header);
+                text.push_str(&block.display_file(false, true).to_string());
+                continue;
+            }
+
+            let container = self.rule_container(name)?;
+            let idx = match containers.iter().position(|x| x.0 == container) {
+                Some(idx) => idx,
+                None => {
+                    containers.push((container, String::new()));
+                    containers.len() - 1
+                }
+            };
+            let members = &mut containers[idx].1;
+            for line in header.lines() {
+                members.push_str(&format!("    {}\n", line));
+            }
+            for content in &block.contents {
+                members.push_str(&format!("    {}\n", content.display_at(1)));
+            }
+        }
+        for (container, members) in containers {
+            text.push_str(&format!("{} = {{\n{}}}\n", container, members));
+        }
+        Ok(text)
+    }
+
+    /// Writes every rule that was changed by scripts or merged from several mods to an output
+    /// mod, in files that the game loads in place of the existing definitions.
+    ///
+    /// If `history_comments` is set, each rule is preceded by comments listing where it was
+    /// defined and how scripts changed it.
+    pub(in crate::rules) fn write_output(
+        &self,
+        lua: &Lua,
+        game: Game,
+        extension: &str,
+        out_dir: &Path,
+        history_comments: bool,
+    ) -> Result<()> {
+        let changed = self.changed_rules(lua)?;
+        if changed.is_empty() {
+            return Ok(());
+        }
+
+        let encoding = game.file_encoding(&self.path);
+        let dir = out_dir.join(&self.path);
+        let write = |file_name: &str, data: Vec<u8>| -> Result<()> {
+            let path = dir.join(file_name);
+            debug!("Writing {}", path.display());
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(&path, data)?;
+            Ok(())
+        };
+
+        match self.mode {
+            ResolverMode::FileReplace => {
+                // each rule is a whole file, which is replaced by writing a file with the same
+                // path.
+                for (name, _, rule) in changed {
+                    let block = match rule.value {
+                        PdxRelationValue::Block(block) => block,
+                        _ => bail!("File {} in {} must contain a block.", name, self.path),
+                    };
+                    let mut text = self.history_header(lua, name, history_comments)?;
+                    match self.used_source(&self.map[name]) {
+                        Some(source) => {
+                            // the file is emitted through its syntax tree, so the comments and
+                            // formatting of everything that is unchanged are kept.
+                            let path = &source.file.path;
+                            let (file, _) = PdxSourceFile::parse_recovering(name, &fs::read(path)?)
+                                .with_context(|| format!("Could not parse {}", path.display()))?;
+                            text.push_str(&file.emit(&block));
+                            write(name, file.encoding().encode(&text)?)?
+                        }
+                        None => {
+                            text.push_str(&block.display_file(false, true).to_string());
+                            write(name, encoding.encode(&text)?)?
+                        }
+                    }
+                }
+            }
+            ResolverMode::Fios | ResolverMode::Lios | ResolverMode::Merge => {
+                let file_name = match self.mode {
+                    ResolverMode::Fios => FIRST_FILE_NAME,
+                    _ => LAST_FILE_NAME,
+                };
+                let rules = changed.into_iter().map(|x| (x.0, x.2)).collect();
+                let text = self.render_rules(lua, rules, history_comments)?;
+                write(&format!("{}{}", file_name, extension), encoding.encode(&text)?)?;
+            }
+            ResolverMode::Append => {
+                // the game appends every definition together, so only the added members can be
+                // written without duplicating the existing ones.
+                let mut rules = Vec::new();
+                for (name, original, rule) in changed {
+                    match (original.map(|x| &x.value), rule.value) {
+                        (Some(PdxRelationValue::Block(old)), PdxRelationValue::Block(new)) => {
+                            let mut added = Vec::new();
+                            for entry in old.diff(&new).entries {
+                                match entry.change {
+                                    PdxDiffChange::Inserted { content, .. }
+                                        if entry.path.is_empty() =>
+                                    {
+                                        added.push(content)
+                                    }
+                                    _ => bail!(
+                                        "Rule {} in {} can only be added to, as the game \
+                                         appends every definition of it together.",
+                                        name,
+                                        self.path,
+                                    ),
+                                }
+                            }
+                            rules.push((name, PdxRelation {
+                                value: PdxRelationValue::Block(PdxBlock::new(added)),
+                                ..rule
+                            }));
+                        }
+                        (None, value) => rules.push((name, PdxRelation { value, ..rule })),
+                        (Some(_), _) => bail!(
+                            "Rule {} in {} must contain a block, as the game appends every \
+                             definition of it together.",
+                            name,
+                            self.path,
+                        ),
+                    }
+                }
+                let text = self.render_rules(lua, rules, history_comments)?;
+                write(&format!("{}{}", LAST_FILE_NAME, extension), encoding.encode(&text)?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(src: &str) -> PdxBlock {
+        PdxBlock::parse_file("test.txt", src.as_bytes()).unwrap()
+    }
+
+    fn scope(global: &Arc<PdxVariables>, src: &str) -> PdxVariables {
+        let mut variables = PdxVariables::with_parent(global.clone());
+        variables.add_definitions(&parse(src)).unwrap();
+        variables
+    }
+
+    #[test]
+    fn resolves_local_variables() {
+        let global = Arc::new(PdxVariables::new());
+        let used = scope(&global, "@cost = 10");
+        let merged = scope(&global, "@cost = 20 @upkeep = 2");
+        let mut block = parse("rule = { cost = @cost upkeep = @[ upkeep * 2 ] other = @global }");
+        let mut visitor = LocalVariables { scopes: vec![&used, &merged], error: None };
+        block.walk_mut(&mut visitor);
+        assert!(visitor.error.is_none());
+        assert_eq!(
+            block.display_file(false, false).to_string().trim_end(),
+            "rule = { cost = 10 upkeep = 4 other = @global }",
+        );
+    }
+}
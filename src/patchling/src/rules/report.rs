@@ -0,0 +1,225 @@
+use crate::{
+    pdx::{PdxMergeConflict, PdxSpan},
+    rules::{ResolvedRules, ResolverMode, RuleChange, RuleInfo, RuleSource},
+};
+use mlua::{prelude::LuaResult, Lua};
+use serde::*;
+use std::{fmt, sync::Arc};
+
+/// A report of the rules and files in a data directory that are defined by more than one data
+/// root.
+#[derive(Serialize, Clone, Debug)]
+pub struct ConflictReport {
+    pub directory: String,
+    pub mode: ResolverMode,
+    pub rules: Vec<RuleConflict>,
+    pub files: Vec<FileConflict>,
+}
+
+/// A rule that is defined more than once.
+#[derive(Serialize, Clone, Debug)]
+pub struct RuleConflict {
+    pub name: Arc<str>,
+    /// Every definition of the rule, in load order.
+    pub definitions: Vec<RuleDefinition>,
+    /// The conflicts found while merging the definitions, if the directory is merged.
+    pub conflicts: Vec<PdxMergeConflict>,
+}
+
+/// A single definition of a rule.
+#[derive(Serialize, Clone, Debug)]
+pub struct RuleDefinition {
+    /// The name of the data root the definition was loaded from.
+    pub source: Arc<str>,
+    pub span: Option<PdxSpan>,
+    /// Whether the game uses the definition.
+    pub status: DefinitionStatus,
+    /// Whether patchling merges the definition into the version of the rule it writes, if the
+    /// rules were resolved with [`ResolverMode::Merge`].
+    pub merged: bool,
+}
+
+/// Whether the game uses a definition of a rule.
+#[derive(Serialize, Copy, Clone, Eq, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DefinitionStatus {
+    /// The definition is used, and all others are ignored.
+    Used,
+    /// The definition is combined with other definitions of the rule by the game.
+    Merged,
+    /// The definition is ignored in favour of another one.
+    Shadowed,
+}
+
+/// A file that replaces files with the same relative path in earlier data roots.
+#[derive(Serialize, Clone, Debug)]
+pub struct FileConflict {
+    pub file_name: String,
+    /// The name of the data root the file is loaded from.
+    pub source: Arc<str>,
+    /// The names of the data roots whose copies of the file are not loaded.
+    pub overwritten: Vec<Arc<str>>,
+}
+
+/// A step in producing the current definition of a rule.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RuleHistoryEntry {
+    /// The rule was defined in a data root.
+    Definition(RuleDefinition),
+    /// The rule was changed by a script.
+    Script(RuleChange),
+}
+
+fn definition_statuses(mode: ResolverMode, sources: &[RuleSource]) -> Vec<DefinitionStatus> {
+    if sources.is_empty() {
+        return Vec::new();
+    }
+    let last = sources.len() - 1;
+    let single = |used: usize| {
+        let status =
+            |i| if i == used { DefinitionStatus::Used } else { DefinitionStatus::Shadowed };
+        (0..sources.len()).map(status).collect()
+    };
+    match mode {
+        ResolverMode::Fios => single(0),
+        // the game itself only uses the last definition of a rule patchling merges.
+        ResolverMode::Lios | ResolverMode::FileReplace | ResolverMode::Merge => single(last),
+        ResolverMode::Append => vec![DefinitionStatus::Merged; sources.len()],
+    }
+}
+
+/// Returns whether patchling merges each definition of a rule into the version it writes.
+fn merged_definitions(mode: ResolverMode, sources: &[RuleSource]) -> Vec<bool> {
+    match mode {
+        ResolverMode::Merge => {
+            // vanilla definitions replace each other, and mod definitions are merged into the
+            // last one.
+            let merged_from = sources.iter().rposition(|x| !x.file.is_mod).unwrap_or(0);
+            let merged = sources.len() - merged_from > 1;
+            (0..sources.len()).map(|i| merged && i >= merged_from).collect()
+        }
+        _ => vec![false; sources.len()],
+    }
+}
+
+impl ResolvedRules {
+    fn definitions(&self, info: &RuleInfo) -> Vec<RuleDefinition> {
+        let statuses = definition_statuses(self.mode, &info.sources);
+        let merged = merged_definitions(self.mode, &info.sources);
+        info.sources
+            .iter()
+            .zip(statuses)
+            .zip(merged)
+            .map(|((source, status), merged)| RuleDefinition {
+                source: self.origins[source.file.origin_mod as usize].clone(),
+                span: source.span.clone(),
+                status,
+                merged,
+            })
+            .collect()
+    }
+
+    /// Returns every step that produced the current definition of a rule: the definitions
+    /// loaded from each data root, followed by the changes scripts made to it. Script changes
+    /// are only included if `lua` is given.
+    pub(in crate::rules) fn history(
+        &self,
+        name: &str,
+        lua: Option<&Lua>,
+    ) -> LuaResult<Option<Vec<RuleHistoryEntry>>> {
+        let info = match self.map.get(name) {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        let mut history: Vec<_> =
+            self.definitions(info).into_iter().map(RuleHistoryEntry::Definition).collect();
+        if let Some(lua) = lua {
+            history.extend(info.changes(lua)?.into_iter().map(RuleHistoryEntry::Script));
+        }
+        Ok(Some(history))
+    }
+
+    pub(in crate::rules) fn conflict_report(&self) -> ConflictReport {
+        let mut rules = Vec::new();
+        for (name, info) in &self.map {
+            if info.sources.len() < 2 {
+                continue;
+            }
+            rules.push(RuleConflict {
+                name: name.clone(),
+                definitions: self.definitions(info),
+                conflicts: info.conflicts.clone(),
+            });
+        }
+
+        let files = self
+            .overwritten_files
+            .iter()
+            .map(|file| FileConflict {
+                file_name: file.file_name.clone(),
+                source: self.origins[file.origin_mod as usize].clone(),
+                overwritten: file
+                    .overwritten
+                    .iter()
+                    .map(|x| self.origins[*x as usize].clone())
+                    .collect(),
+            })
+            .collect();
+
+        ConflictReport { directory: self.path.clone(), mode: self.mode, rules, files }
+    }
+}
+
+impl fmt::Display for DefinitionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            DefinitionStatus::Used => "used",
+            DefinitionStatus::Merged => "merged",
+            DefinitionStatus::Shadowed => "shadowed",
+        })
+    }
+}
+
+impl fmt::Display for RuleDefinition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<8} {}", self.status, self.source)?;
+        if let Some(span) = &self.span {
+            write!(f, " ({})", span)?;
+        }
+        if self.merged {
+            f.write_str(", merged by patchling")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RuleHistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleHistoryEntry::Definition(definition) => fmt::Display::fmt(definition, f),
+            RuleHistoryEntry::Script(change) => fmt::Display::fmt(change, f),
+        }
+    }
+}
+
+impl fmt::Display for ConflictReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for file in &self.files {
+            writeln!(f, "{}: file from {} overwrites:", file.file_name, file.source)?;
+            for source in &file.overwritten {
+                writeln!(f, "    {}", source)?;
+            }
+        }
+        for rule in &self.rules {
+            writeln!(f, "{}:", rule.name)?;
+            for definition in &rule.definitions {
+                writeln!(f, "    {}", definition)?;
+            }
+            for conflict in &rule.conflicts {
+                writeln!(f, "    conflict {}", conflict)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,25 @@
-use crate::rules::{DataRoot, ResolverMode};
+use crate::{
+    pdx::{
+        PdxBlock, PdxBlockContent, PdxInterner, PdxRelation, PdxRelationType, PdxRelationValue,
+        PdxVariables,
+    },
+    rules::{DataRoot, DefaultRuleType, ResolvedRules, ResolverMode, RuleFile, RuleLayout},
+};
 use anyhow::*;
-use mlua::{Lua, Value};
-use std::{fs, path::PathBuf, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 fn check_name_safe(dir: &str) -> Result<()> {
+    if dir.starts_with('/') || dir.split('/').any(|x| x == "..") {
+        bail!("Paths must be relative to the game directory: {}", dir);
+    }
     for ch in dir.chars() {
         match ch {
-            'a'..='z' | '0'..='9' | '_' | '.' => {}
+            'a'..='z' | '0'..='9' | '_' | '.' | '/' => {}
             'A'..='Z' => bail!("Please use lowercase path names, as this is required on Linux."),
             _ => bail!("Invalid character in filename: {:?}", ch),
         }
@@ -15,48 +28,328 @@ fn check_name_safe(dir: &str) -> Result<()> {
 }
 
 struct ResolvedFile {
+    origin_mod: u32,
     source_mod: Option<Arc<str>>,
+    /// The path of the file relative to the resolved directory, using `/` as a separator.
     file_name: String,
     path: PathBuf,
+    /// The files from earlier data roots with the same relative path, which this file replaces.
+    shadowed: Vec<ResolvedFile>,
+}
+
+fn find_files(
+    dir: &Path,
+    relative: &str,
+    extension: &str,
+    found: &mut Vec<(String, PathBuf)>,
+) -> Result<()> {
+    for file in fs::read_dir(dir)? {
+        let file = file?;
+        let file_name = format!("{}{}", relative, file.file_name().to_string_lossy());
+        if file.file_type()?.is_dir() {
+            find_files(&file.path(), &format!("{}/", file_name), extension, found)?;
+        } else if file_name.ends_with(extension) {
+            found.push((file_name, file.path()));
+        }
+    }
+    Ok(())
 }
+
+/// Finds the files the game loads from a directory, in the order it loads them.
+///
+/// Subdirectories are searched recursively, and a file replaces any file with the same path
+/// relative to the directory in an earlier data root. Files are loaded in ASCII order of their
+/// relative paths.
 fn resolve_files(
     roots: &[DataRoot],
     directory: &str,
     extension: &str,
 ) -> Result<Vec<ResolvedFile>> {
-    let mut resolved = Vec::new();
-    for root in roots {
+    let mut resolved: BTreeMap<String, ResolvedFile> = BTreeMap::new();
+    for (origin_mod, root) in roots.iter().enumerate() {
         let source_mod = if root.is_mod { Some(root.name.clone()) } else { None };
 
         let mut root_path = root.root_dir.clone();
         root_path.push(directory);
-        for file in fs::read_dir(&root_path)? {
-            let file = file?;
-            let file_name = file.file_name().to_string_lossy().to_string();
-            if file_name.ends_with(extension) {
-                debug!("Found file: {}", file.path().display());
-                resolved.push(ResolvedFile {
-                    source_mod: source_mod.clone(),
-                    file_name,
-                    path: file.path(),
-                });
+        if !root_path.is_dir() {
+            continue;
+        }
+        let mut found = Vec::new();
+        find_files(&root_path, "", extension, &mut found)?;
+        for (file_name, path) in found {
+            debug!("Found file: {}", path.display());
+            let mut file = ResolvedFile {
+                origin_mod: origin_mod as u32,
+                source_mod: source_mod.clone(),
+                file_name: file_name.clone(),
+                path,
+                shadowed: Vec::new(),
+            };
+            if let Some(mut old) = resolved.remove(&file_name) {
+                debug!("{} replaces {}", file.path.display(), old.path.display());
+                file.shadowed = std::mem::take(&mut old.shadowed);
+                file.shadowed.push(old);
+            }
+            resolved.insert(file_name, file);
+        }
+    }
+    Ok(resolved.into_values().collect())
+}
+
+/// Parses a rules file, recovering from syntax errors so one malformed file does not prevent the
+/// rest of the playset from being loaded.
+fn parse_file(file: &ResolvedFile, interner: &PdxInterner) -> Result<PdxBlock> {
+    let data = fs::read(&file.path)?;
+    let (block, diagnostics) =
+        PdxBlock::parse_file_recovering_interned(&file.file_name, &data, interner)
+            .with_context(|| format!("Could not parse {}", file.path.display()))?;
+    for diagnostic in diagnostics {
+        warn!("Error parsing {}: {}", file.path.display(), diagnostic);
+    }
+    Ok(block)
+}
+
+/// Returns whether a top-level relation defines a file-local `@variable`.
+fn is_variable(rel: &PdxRelation) -> bool {
+    rel.tag.starts_with('@')
+}
+
+/// A rule found in a file, along with the tag of the container block it was in, if any.
+struct FileRule {
+    container: Option<Arc<str>>,
+    name: Arc<str>,
+    rule: PdxRelation,
+}
+
+/// Returns the value of a member of a block that names it, such as `id = foo.1`.
+fn member_name(block: &PdxBlock, tag: &str, interner: &PdxInterner) -> Option<Arc<str>> {
+    block.contents.iter().find_map(|content| match content {
+        PdxBlockContent::Relation(rel) if &*rel.tag == tag => match &rel.value {
+            PdxRelationValue::String(str) => Some(str.clone()),
+            PdxRelationValue::Numeric(num) => Some(interner.intern(&num.to_string())),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+/// Splits the contents of a file into the rules it defines.
+fn split_rules(
+    file: &ResolvedFile,
+    block: PdxBlock,
+    layout: RuleLayout,
+    interner: &PdxInterner,
+) -> Vec<FileRule> {
+    let mut rules = Vec::new();
+    for content in block.contents {
+        let rel = match content {
+            // variables are not rules, and are added to the file's scope instead.
+            PdxBlockContent::Relation(rel) if is_variable(&rel) => continue,
+            PdxBlockContent::Relation(rel) => rel,
+            PdxBlockContent::String(str) => {
+                warn!("Ignoring bare value {:?} in {}.", str, file.path.display());
+                continue;
+            }
+        };
+        match (layout, &rel.value) {
+            (RuleLayout::Tag, _) => {
+                rules.push(FileRule { container: None, name: rel.tag.clone(), rule: rel })
+            }
+            (RuleLayout::Id, PdxRelationValue::Block(block)) => {
+                match member_name(block, "id", interner) {
+                    Some(name) => rules.push(FileRule { container: None, name, rule: rel }),
+                    None => warn!("Ignoring {} without an id in {}.", rel.tag, file.path.display()),
+                }
+            }
+            // declarations such as `namespace = foo` only apply to the file they are in.
+            (RuleLayout::Id, _) => {}
+            (RuleLayout::Container, _) => {
+                let container = rel.tag.clone();
+                let members = match rel.value {
+                    PdxRelationValue::Block(block) => block.contents,
+                    _ => {
+                        warn!(
+                            "Ignoring {} in {}, as it is not a block.",
+                            rel.tag,
+                            file.path.display()
+                        );
+                        continue;
+                    }
+                };
+                for member in members {
+                    match member {
+                        PdxBlockContent::Relation(rel) => {
+                            let name = match &rel.value {
+                                PdxRelationValue::Block(block) => {
+                                    member_name(block, "name", interner)
+                                }
+                                _ => None,
+                            };
+                            match name {
+                                Some(name) => rules.push(FileRule {
+                                    container: Some(container.clone()),
+                                    name,
+                                    rule: rel,
+                                }),
+                                None => warn!(
+                                    "Ignoring {} without a name in {}.",
+                                    rel.tag,
+                                    file.path.display()
+                                ),
+                            }
+                        }
+                        PdxBlockContent::String(str) => {
+                            warn!("Ignoring bare value {:?} in {}.", str, file.path.display())
+                        }
+                    }
+                }
             }
         }
     }
-    Ok(resolved)
+    rules
+}
+
+/// Returns the variables defined at the top of a file, on top of the global scripted variables.
+fn file_variables(
+    file: &ResolvedFile,
+    block: &PdxBlock,
+    variables: &Arc<PdxVariables>,
+) -> PdxVariables {
+    let mut scope = PdxVariables::with_parent(variables.clone());
+    if let Err(e) = scope.add_definitions(block) {
+        warn!("Error reading variables in {}: {}", file.path.display(), e);
+    }
+    scope
 }
 
-pub fn load_rules<'a>(
-    lua: &'a Lua,
+pub fn resolve_rules(
     roots: &[DataRoot],
     mode: ResolverMode,
+    layout: RuleLayout,
     directory: &str,
     extension: &str,
-) -> Result<Value<'a>> {
+    variables: &Arc<PdxVariables>,
+    interner: &Arc<PdxInterner>,
+) -> Result<ResolvedRules> {
     check_name_safe(directory)?;
     check_name_safe(extension)?;
 
-    let mut files = resolve_files(roots, directory, extension)?;
+    let mut rules = ResolvedRules::new(
+        DefaultRuleType::RuleEquals,
+        mode,
+        layout,
+        directory,
+        roots,
+        variables.clone(),
+        interner.clone(),
+    );
+    let files = resolve_files(roots, directory, extension)?;
+    if mode == ResolverMode::Merge {
+        // mods often replace a vanilla file with a modified copy of it, so the definitions in
+        // vanilla files that were replaced are still needed to merge against.
+        for shadowed in files.iter().flat_map(|x| &x.shadowed) {
+            if shadowed.source_mod.is_none() {
+                let block = parse_file(shadowed, interner)?;
+                for found in split_rules(shadowed, block, layout, interner) {
+                    rules.add_shadowed_base(found.name, found.rule);
+                }
+            }
+        }
+    }
 
-    Ok(Value::Nil)
+    for file in files {
+        if !file.shadowed.is_empty() {
+            let overwritten = file.shadowed.iter().map(|x| x.origin_mod).collect();
+            rules.add_overwritten_file(&file.file_name, file.origin_mod, overwritten);
+        }
+
+        let block = parse_file(&file, interner)?;
+        let scope = file_variables(&file, &block, variables);
+        let rule_file = Arc::new(RuleFile {
+            origin_mod: file.origin_mod,
+            is_mod: file.source_mod.is_some(),
+            path: file.path.clone(),
+            variables: scope,
+        });
+        if mode == ResolverMode::FileReplace {
+            let name = interner.intern(&file.file_name);
+            let rule = PdxRelation {
+                tag: name.clone(),
+                relation: PdxRelationType::Normal,
+                value: PdxRelationValue::Block(block),
+                span: None,
+            };
+            rules.add_rule_from_sources(&rule_file, None, name, rule);
+            continue;
+        }
+        for found in split_rules(&file, block, layout, interner) {
+            rules.add_rule_from_sources(&rule_file, found.container, found.name, found.rule);
+        }
+    }
+    rules.finish_init();
+    Ok(rules)
+}
+
+/// Loads the global scripted variables defined in `common/scripted_variables`.
+pub fn load_scripted_variables(roots: &[DataRoot], interner: &PdxInterner) -> Result<PdxVariables> {
+    let mut variables = PdxVariables::new();
+    for file in resolve_files(roots, "common/scripted_variables", ".txt")? {
+        variables.add_definitions(&parse_file(&file, interner)?)?;
+    }
+    Ok(variables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(layout: RuleLayout, src: &str) -> Vec<(Option<String>, String, String)> {
+        let file = ResolvedFile {
+            origin_mod: 0,
+            source_mod: None,
+            file_name: "test.txt".to_string(),
+            path: PathBuf::from("test.txt"),
+            shadowed: Vec::new(),
+        };
+        let block = PdxBlock::parse_file("test.txt", src.as_bytes()).unwrap();
+        split_rules(&file, block, layout, &PdxInterner::new())
+            .into_iter()
+            .map(|x| {
+                (x.container.map(|x| x.to_string()), x.name.to_string(), x.rule.tag.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_rules_by_tag() {
+        assert_eq!(split(RuleLayout::Tag, "@cost = 10 a = { } b = 1 c"), vec![
+            (None, "a".to_string(), "a".to_string()),
+            (None, "b".to_string(), "b".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn splits_rules_by_id() {
+        let src = "namespace = test\n\
+                   country_event = { id = test.1 }\n\
+                   planet_event = { id = test.2 }\n\
+                   country_event = { is_triggered_only = yes }\n";
+        assert_eq!(split(RuleLayout::Id, src), vec![
+            (None, "test.1".to_string(), "country_event".to_string()),
+            (None, "test.2".to_string(), "planet_event".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn splits_rules_in_containers() {
+        let src = "spriteTypes = {\n\
+                       spriteType = { name = \"GFX_a\" texturefile = \"a.dds\" }\n\
+                       spriteType = { texturefile = \"b.dds\" }\n\
+                   }\n\
+                   guiTypes = { containerWindowType = { name = \"window\" } }\n";
+        assert_eq!(split(RuleLayout::Container, src), vec![
+            (Some("spriteTypes".to_string()), "GFX_a".to_string(), "spriteType".to_string()),
+            (Some("guiTypes".to_string()), "window".to_string(), "containerWindowType".to_string()),
+        ]);
+    }
 }
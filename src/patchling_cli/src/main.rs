@@ -1,8 +1,12 @@
 use anyhow::*;
 use clap::{AppSettings, Clap};
-use patchling::{CompilerBuilder, Game};
-use std::{env, path::PathBuf};
-use tracing::Level;
+use patchling::{
+    pdx::{PdxBlock, PdxQuery},
+    rules::{DataRoot, RulesManager},
+    CompilerBuilder, Game,
+};
+use std::{env, fs, path::PathBuf};
+use tracing::{warn, Level};
 
 /// A tool for making mods for Stellaris and other Paradox Interactive games.
 #[derive(Clap)]
@@ -12,13 +16,158 @@ struct Opts {
     /// Print additional debugging output.
     #[clap(short, long)]
     verbose: bool,
+    /// The game to make mods for.
+    #[clap(long, default_value = "stellaris")]
+    game: Game,
     /// The directory that contains the base game data.
     #[clap(long)]
     game_data: Option<PathBuf>,
+    /// Precede each rule in the output mod with comments explaining where it came from.
+    #[clap(long)]
+    history_comments: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clap)]
+enum Command {
+    /// Prints every relation in a set of files that matches a query.
+    Query(QueryOpts),
+    /// Lists the rules and files in a data directory that are defined more than once.
+    Conflicts(ConflictsOpts),
+    /// Lists every definition of a rule, in load order, and whether the game uses each of them.
+    Explain(ExplainOpts),
+}
+
+#[derive(Clap)]
+struct QueryOpts {
+    /// The query to run, such as `*[.tier >= 3].modifier.*_mult`.
+    query: String,
+    /// The files to search.
+    #[clap(required = true)]
+    files: Vec<PathBuf>,
+}
+
+#[derive(Clap)]
+struct ConflictsOpts {
+    /// The data directory to check, such as `common/technology`.
+    directory: String,
+    /// The extension of the files to check.
+    #[clap(long, default_value = ".txt")]
+    extension: String,
+    /// The root directory of a mod in the playset. Mods are loaded in the order given.
+    #[clap(long = "mod")]
+    mods: Vec<PathBuf>,
+    /// Prints the report as JSON.
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Clap)]
+struct ExplainOpts {
+    /// The data directory the rule is defined in, such as `common/technology`.
+    directory: String,
+    /// The name of the rule.
+    rule: String,
+    /// The extension of the files the rule is defined in.
+    #[clap(long, default_value = ".txt")]
+    extension: String,
+    /// The root directory of a mod in the playset. Mods are loaded in the order given.
+    #[clap(long = "mod")]
+    mods: Vec<PathBuf>,
+    /// Prints the definitions as JSON.
+    #[clap(long)]
+    json: bool,
+}
+
+/// Creates a rules manager for the base game and a list of mods.
+fn load_playset(
+    game: Game,
+    game_data: Option<PathBuf>,
+    mods: Vec<PathBuf>,
+) -> Result<RulesManager> {
+    let game_data = match game_data {
+        Some(data) => data,
+        None => match game.find_game_data()?.into_iter().next() {
+            Some(data) => data,
+            None => bail!(
+                "Could not find game data directory. Please explicitly set it using --game-data."
+            ),
+        },
+    };
+
+    let mut rules = RulesManager::new(game);
+    rules.add_data_root(DataRoot::vanilla(game_data));
+    for path in mods {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => path.display().to_string(),
+        };
+        rules.add_data_root(DataRoot::mod_data(name, path));
+    }
+    Ok(rules)
+}
+
+fn run_conflicts(game: Game, game_data: Option<PathBuf>, opts: ConflictsOpts) -> Result<()> {
+    let mut rules = load_playset(game, game_data, opts.mods)?;
+    let report = rules.conflict_report(&opts.directory, &opts.extension)?;
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report);
+    }
+    Ok(())
+}
+
+fn run_explain(game: Game, game_data: Option<PathBuf>, opts: ExplainOpts) -> Result<()> {
+    let mut rules = load_playset(game, game_data, opts.mods)?;
+    let history = match rules.explain(&opts.directory, &opts.extension, &opts.rule)? {
+        Some(history) => history,
+        None => bail!("Rule {} is not defined in {}.", opts.rule, opts.directory),
+    };
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&history)?);
+    } else {
+        println!("{}:", opts.rule);
+        for entry in &history {
+            println!("    {}", entry);
+        }
+    }
+    Ok(())
+}
+
+fn run_query(opts: QueryOpts) -> Result<()> {
+    let query = PdxQuery::parse(&opts.query)?;
+    for file in &opts.files {
+        let data = fs::read(file)?;
+        let (block, diagnostics) =
+            PdxBlock::parse_file_recovering(&file.display().to_string(), &data)?;
+        for diagnostic in diagnostics {
+            warn!("{}", diagnostic);
+        }
+        for found in query.find(&block) {
+            let rel = found.relation;
+            let path = found.path.join(".");
+            match &rel.span {
+                Some(span) => println!("{}: {} {} {}", span, path, rel.relation, rel.value),
+                None => println!("{} {} {}", path, rel.relation, rel.value),
+            }
+        }
+    }
+    Ok(())
 }
 
 fn main_res(opts: Opts) -> Result<()> {
-    let mut builder = CompilerBuilder::new(Game::Stellaris);
+    match opts.command {
+        Some(Command::Query(query)) => return run_query(query),
+        Some(Command::Conflicts(conflicts)) => {
+            return run_conflicts(opts.game, opts.game_data, conflicts)
+        }
+        Some(Command::Explain(explain)) => return run_explain(opts.game, opts.game_data, explain),
+        None => {}
+    }
+
+    let mut builder = CompilerBuilder::new(opts.game).history_comments(opts.history_comments);
     if let Some(data) = opts.game_data {
         builder = builder.game_data(data);
     }
@@ -17,7 +17,8 @@ end
 local function compile_to_src(file)
     local src = read_all(file)
     local ast = mlc.new():src_to_ast(src)
-    return into_src(ast)
+    -- only the source is written out; the line map is used when scripts are compiled at runtime.
+    return (into_src(ast))
 end
 
 lfs.mkdir("../target/lua_src")
@@ -1,9 +1,18 @@
 local mlc = require "metalua.compiler"
 local luasrcdiet = require "luasrcdiet.init"
 
+-- Newlines are kept, so the lines of the minified source still match the line map `ast_to_src` made
+-- for the generated source.
+local minify_opts = {}
+for k, v in pairs(luasrcdiet.MAXIMUM_OPTS) do
+    minify_opts[k] = v
+end
+minify_opts.eols = false
+minify_opts.emptylines = false
+
 local function compile_and_minify(source, name)
     local lua_source = mlc.new():src_to_lua(source, name);
-    return luasrcdiet.optimize(luasrcdiet.MAXIMUM_OPTS, lua_source)
+    return luasrcdiet.optimize(minify_opts, lua_source)
 end
 
 return compile_and_minify
\ No newline at end of file
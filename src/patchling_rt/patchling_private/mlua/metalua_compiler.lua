@@ -113,8 +113,14 @@ function CONV:lexstream_to_ast(lx, name)
 end
 
 local ast_to_lua_impl = require 'metalua.compiler.ast_to_src'
+local register_line_map = (require 'patchling_private.privileged.traceback').register_line_map
 function CONV:ast_to_lua(ast, name)
-    return ast_to_lua_impl(ast), name
+    local lua, line_map = ast_to_lua_impl(ast)
+    if name and name:sub(1, 1) == '@' then
+        -- lets tracebacks report lines of the metalua source rather than the generated source.
+        register_line_map(name:sub(2), line_map)
+    end
+    return lua, name
 end
 
 local loadstring = loadstring -- save this to avoid later loops
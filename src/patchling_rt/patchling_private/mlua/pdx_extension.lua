@@ -0,0 +1,203 @@
+-- A metalua extension for writing PDX literals directly in scripts. It is enabled with
+-- `-{ extension ("pdx", ...) }`, after which `pdx [[ ... ]]` and `pdx "..."` are compiled into a
+-- table constructor for the contents of the block, in the same layout `pdx::model` mirrors blocks
+-- into Lua with. The PDX source is parsed once, when the script is compiled.
+--
+-- Lua expressions can be spliced into a literal with `$(...)`. A splice may be used as a tag, a
+-- bare string, or the value of a relation. A spliced value is stored according to its type when
+-- the literal is evaluated: numbers become `num`, strings become `val`, booleans become `yes` or
+-- `no`, and tables are used as blocks.
+--
+--     local cost = pdx [[ cost = { energy = @tier3cost minerals = $(base * 2) } ]]
+
+local error = error
+local pairs = pairs
+local pcall = pcall
+local string_find = string.find
+local string_format = string.format
+local string_match = string.match
+local string_sub = string.sub
+local table_concat = table.concat
+local table_sort = table.sort
+local tonumber = tonumber
+local tostring = tostring
+local type = type
+
+-- Splices are replaced with identifiers starting with this prefix before the literal is parsed.
+local splice_prefix = "__patchling_splice_"
+
+local function literal_error(ast, message, ...)
+    local line = ast.lineinfo and ast.lineinfo.first and ast.lineinfo.first.line
+    error(string_format("line %s: PDX literal: "..message, tostring(line or "?"), ...), 0)
+end
+
+-- Replaces each splice in a literal with a placeholder. Returns the new source, and the source of
+-- each spliced expression.
+local function extract_splices(ast, text)
+    local parts, splices = { }, { }
+    local pos = 1
+    while true do
+        local start = string_find(text, "$(", pos, true)
+        if not start then
+            break
+        end
+
+        -- Find the parenthesis closing the splice, skipping over any Lua strings in it.
+        local depth, quote, finish = 0, nil, nil
+        local i = start + 1
+        while i <= #text do
+            local c = string_sub(text, i, i)
+            if quote then
+                if c == "\\" then
+                    i = i + 1
+                elseif c == quote then
+                    quote = nil
+                end
+            elseif c == '"' or c == "'" then
+                quote = c
+            elseif c == "(" then
+                depth = depth + 1
+            elseif c == ")" then
+                depth = depth - 1
+                if depth == 0 then
+                    finish = i
+                    break
+                end
+            end
+            i = i + 1
+        end
+        if not finish then
+            literal_error(ast, "unterminated splice")
+        end
+
+        splices[#splices + 1] = string_sub(text, start + 2, finish - 1)
+        parts[#parts + 1] = string_sub(text, pos, start - 1)
+        parts[#parts + 1] = " "..splice_prefix..#splices.." "
+        pos = finish + 1
+    end
+    parts[#parts + 1] = string_sub(text, pos)
+    return table_concat(parts), splices
+end
+
+-- Returns the index of the splice a string is the placeholder for, or nil if it is not one.
+local function splice_index(ast, str)
+    local index = string_match(str, "^"..splice_prefix.."(%d+)$")
+    if index then
+        return tonumber(index)
+    elseif string_find(str, splice_prefix, 1, true) then
+        literal_error(ast, "a splice must be a whole tag, bare string or value")
+    end
+end
+
+return function(M)
+    -- Parses the source of a spliced expression.
+    local function parse_splice(ast, src)
+        local ok, result = pcall(function()
+            local lx = M.lexer:newstream(src, "<pdx splice>")
+            local expr = M.expr(lx)
+            if lx:peek().tag ~= "Eof" then
+                error("unexpected tokens after the expression", 0)
+            end
+            return expr
+        end)
+        if not ok then
+            literal_error(ast, "could not parse splice `%s`: %s", src, tostring(result))
+        end
+        return result
+    end
+
+    -- Converts a parsed PDX value into the AST of a constructor for it.
+    local function to_ast(ast, value, splices)
+        local value_type = type(value)
+        if value_type == "string" then
+            local index = splice_index(ast, value)
+            if index then
+                return splices[index]
+            end
+            return { tag = "String", value }
+        elseif value_type == "number" then
+            return { tag = "Number", value }
+        elseif value_type == "boolean" then
+            return { tag = value and "True" or "False" }
+        end
+
+        -- The keys are sorted, so the same literal always compiles to the same source.
+        local table_ast, keys = { tag = "Table" }, { }
+        for k in pairs(value) do
+            if type(k) ~= "number" then
+                keys[#keys + 1] = k
+            end
+        end
+        for i = 1, #value do
+            table_ast[i] = to_ast(ast, value[i], splices)
+        end
+        table_sort(keys)
+        for _, k in pairs(keys) do
+            table_ast[#table_ast + 1] = {
+                tag = "Pair", { tag = "String", k }, to_ast(ast, value[k], splices),
+            }
+        end
+        return table_ast
+    end
+
+    -- Converts the contents of a parsed block. A relation whose value is spliced is created by
+    -- `pdx.relation` when the literal is evaluated, as the kind of its value is not known yet.
+    local function block_to_ast(ast, contents, splices)
+        local table_ast = { tag = "Table" }
+        for i = 1, #contents do
+            local content = contents[i]
+            local index = type(content) == "table" and content.val and splice_index(ast, content.val)
+            if index then
+                table_ast[i] = {
+                    tag = "Call",
+                    { tag = "Index", { tag = "Id", "pdx" }, { tag = "String", "relation" } },
+                    to_ast(ast, content.tag, splices),
+                    splices[index],
+                    content.relation and { tag = "String", content.relation } or nil,
+                }
+            elseif type(content) == "table" and content.block then
+                local relation = { }
+                for k, v in pairs(content) do
+                    relation[k] = v
+                end
+                relation.block = nil
+                local relation_ast = to_ast(ast, relation, splices)
+                relation_ast[#relation_ast + 1] = {
+                    tag = "Pair",
+                    { tag = "String", "block" },
+                    block_to_ast(ast, content.block, splices),
+                }
+                table_ast[i] = relation_ast
+            else
+                table_ast[i] = to_ast(ast, content, splices)
+            end
+        end
+        return table_ast
+    end
+
+    local function transform(ast)
+        if ast.tag ~= "Call" or #ast ~= 2 then
+            return
+        end
+        local f, arg = ast[1], ast[2]
+        if f.tag ~= "Id" or f[1] ~= "pdx" or arg.tag ~= "String" then
+            return
+        end
+
+        local src, splice_srcs = extract_splices(ast, arg[1])
+        local splices = { }
+        for i = 1, #splice_srcs do
+            splices[i] = parse_splice(ast, splice_srcs[i])
+        end
+
+        -- `pdx` is registered by the compiler after the runtime is loaded, so it is only looked up
+        -- once a literal is compiled.
+        local ok, contents = pcall(pdx.parse, src)
+        if not ok then
+            literal_error(ast, "%s", tostring(contents))
+        end
+        return block_to_ast(ast, contents, splices)
+    end
+
+    M.expr.transformers:add(transform)
+end
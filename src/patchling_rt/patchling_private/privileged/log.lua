@@ -0,0 +1,34 @@
+-- NOTE: This is privileged code and has access to functions that should not be available to user code.
+--       Take extra care when editing this file.
+
+-- The `log` module available to scripts, which forwards messages to the logger of the compiler.
+
+local select = select
+local table_concat = table.concat
+local tostring = tostring
+
+local locate = (require "patchling_private.privileged.traceback").locate
+
+-- Creates the `log` module. `sink` is called with the level of each message, the message, and the
+-- mod, module and line it was logged from.
+local function new_log(sink)
+    local function logger(level)
+        return function(...)
+            local parts = {}
+            for i = 1, select('#', ...) do
+                parts[i] = tostring((select(i, ...)))
+            end
+            local mod_name, module_name, line = locate(2)
+            sink(level, table_concat(parts, " "), mod_name, module_name, line)
+        end
+    end
+    return {
+        trace = logger "trace",
+        debug = logger "debug",
+        info = logger "info",
+        warn = logger "warn",
+        error = logger "error",
+    }
+end
+
+return new_log
@@ -59,18 +59,22 @@ local register_file = (require "patchling_private.privileged.traceback").registe
 
 ----------------------------------------------------------------------
 -- Take a Lua module name, return the open file and its name,
--- or <false> and an error message.
+-- or <false> and an error message. Only the library directories of
+-- the given mod are searched, or Patchling's own modules by default.
 ----------------------------------------------------------------------
-function M.findfile(name, path_string, no, extension)
+function M.findfile(name, path_string, no, extension, mod_name)
     name = string_gsub(name, '%.', "/")
+    mod_name = mod_name or "patchling"
     local errors = { }
     for _, path in ipairs(path_cache) do
-        local filename = path.root .. name .. extension
-        local file = io_open(filename, 'r')
-        if file then
-            return file, filename, name .. extension, path.mod_name
+        if path.mod_name == mod_name then
+            local filename = path.root .. name .. extension
+            local file = io_open(filename, 'r')
+            if file then
+                return file, filename, name .. extension, path.mod_name
+            end
+            table_insert(errors, string_format("\tno "..no.." file %q", filename))
         end
-        table_insert(errors, string_format("\tno "..no.." file %q", filename))
     end
     return false, '\n' .. table_concat(errors, "\n") .. '\n'
 end
@@ -78,10 +82,8 @@ end
 ----------------------------------------------------------------------
 -- Load a Lua source file.
 ----------------------------------------------------------------------
-function M.lua_loader (name)
-    checks('string')
-
-    local file, filename_or_msg, short_name, mod_name = M.findfile(name, M.path, "lua", ".lua")
+local function load_lua(name, mod_name)
+    local file, filename_or_msg, short_name, mod_name = M.findfile(name, M.path, "lua", ".lua", mod_name)
     if not file then
         return filename_or_msg
     end
@@ -97,16 +99,18 @@ function M.lua_loader (name)
         return fn
     end
 end
+function M.lua_loader (name)
+    checks('string')
+    return load_lua(name)
+end
 
 ----------------------------------------------------------------------
 -- Load a metalua source file.
 ----------------------------------------------------------------------
-function M.metalua_loader (name)
-    checks('string')
-
+local function load_metalua(name, mod_name)
     local compiler = M.loaded["metalua.compiler"]
     if compiler then
-        local file, filename_or_msg, short_name, mod_name = M.findfile(name, M.mpath, "metalua", ".mlua")
+        local file, filename_or_msg, short_name, mod_name = M.findfile(name, M.mpath, "metalua", ".mlua", mod_name)
         if not file then
             return filename_or_msg
         end
@@ -125,6 +129,28 @@ function M.metalua_loader (name)
         return "\tno metalua compiler\n"
     end
 end
+function M.metalua_loader (name)
+    checks('string')
+    return load_metalua(name)
+end
+
+----------------------------------------------------------------------
+-- Load a module from the library directories of a mod, returning
+-- the loaded function, or <nil> and an error message.
+----------------------------------------------------------------------
+function M.find_module (name, mod_name)
+    checks('string', 'string')
+
+    local lua_fn = load_lua(name, mod_name)
+    if type(lua_fn) == "function" then
+        return lua_fn
+    end
+    local metalua_fn = load_metalua(name, mod_name)
+    if type(metalua_fn) == "function" then
+        return metalua_fn
+    end
+    return nil, lua_fn .. metalua_fn
+end
 
 ----------------------------------------------------------------------
 -- Placed after lua/luac loader, so precompiled files have
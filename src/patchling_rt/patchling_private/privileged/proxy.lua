@@ -0,0 +1,284 @@
+-- NOTE: This is privileged code and has access to functions that should not be available to user code.
+--       Take extra care when editing this file.
+
+-- Proxies for the Lua mirrors of rules, which record every change scripts make to them.
+--
+-- A proxy is a userdata whose metatable forwards reads and writes to the mirror it wraps. Tables
+-- read from a proxy are wrapped in proxies of their own. Userdata is used rather than an empty table,
+-- as LuaJIT only respects `__len` on tables when it is built with Lua 5.2 compatibility. Lua 5.1 does
+-- not let `pairs`, `ipairs` or `table.insert` be overridden for a value, so the global functions that
+-- inspect or edit tables are replaced by versions that understand proxies, and `type` reports
+-- proxies as tables.
+
+local l_ipairs = ipairs
+local l_next = next
+local l_pairs = pairs
+local l_unpack = unpack
+local getmetatable = getmetatable
+local newproxy = newproxy
+local rawset = rawset
+local select = select
+local setmetatable = setmetatable
+local table_clone = table.clone
+local table_concat = table.concat
+local table_insert = table.insert
+local table_isempty = table.isempty
+local table_nkeys = table.nkeys
+local table_remove = table.remove
+local table_sort = table.sort
+local tostring = tostring
+local type = type
+
+local locate = (require "patchling_private.privileged.traceback").locate
+
+-- Maps each proxy to its state, which is `{ target = table, root = root, path = keys }`. The root is
+-- shared by every proxy of a mirror, and is `{ changes = log, proxies = { [table] = proxy } }`.
+local states = setmetatable({}, { __mode = "k" })
+
+
+local function append(path, key)
+    local new_path = {}
+    for i = 1, #path do
+        new_path[i] = path[i]
+    end
+    new_path[#new_path + 1] = tostring(key)
+    return new_path
+end
+
+-- Records a change, along with the mod, module and source line of the script that made it. `level`
+-- has the same meaning as for `error`.
+local function record(changes, kind, path, level)
+    local mod_name, module_name, line = locate(level + 1)
+    changes[#changes + 1] = {
+        kind = kind,
+        path = path,
+        mod_name = mod_name,
+        module = module_name,
+        line = line,
+    }
+end
+
+-- Every proxy is created from this one, so they all share its metatable.
+local prototype = newproxy(true)
+local metatable = getmetatable(prototype)
+-- scripts must not be able to replace the functions that record changes.
+metatable.__metatable = false
+
+local function wrap(value, root, path)
+    if type(value) ~= "table" then
+        return value
+    end
+    local proxy = root.proxies[value]
+    if not proxy then
+        -- the path of a nested table is the one it was first read from.
+        proxy = newproxy(prototype)
+        states[proxy] = { target = value, root = root, path = path }
+        root.proxies[value] = proxy
+    end
+    return proxy
+end
+
+-- Copies a table from a mirror and every table in it. Tables that appear more than once are copied
+-- once, so the copy has the same shape.
+local function deep_copy(value, copies)
+    if type(value) ~= "table" then
+        return value
+    end
+    local copy = copies[value]
+    if not copy then
+        copy = {}
+        copies[value] = copy
+        for k, v in l_next, value do
+            rawset(copy, k, deep_copy(v, copies))
+        end
+    end
+    return copy
+end
+
+-- Replaces every proxy in a value with the table it wraps, so mirrors never contain proxies. Tables
+-- wrapped by proxies of a different root are copied, so two mirrors never share a table. `root` is
+-- `nil` for a value that is about to become a new mirror.
+local function unwrap(value, root, seen)
+    local state = states[value]
+    if state then
+        if state.root == root then
+            return state.target
+        end
+        return deep_copy(state.target, {})
+    end
+    if type(value) ~= "table" then
+        return value
+    end
+    seen = seen or {}
+    if not seen[value] then
+        seen[value] = true
+        for k, v in l_next, value do
+            if states[v] or type(v) == "table" then
+                rawset(value, k, unwrap(v, root, seen))
+            end
+        end
+    end
+    return value
+end
+
+function metatable.__index(proxy, key)
+    local state = states[proxy]
+    return wrap(state.target[key], state.root, append(state.path, key))
+end
+function metatable.__newindex(proxy, key, value)
+    local state = states[proxy]
+    state.target[key] = unwrap(value, state.root)
+    record(state.root.changes, value == nil and "delete" or "set", append(state.path, key), 2)
+end
+function metatable.__len(proxy)
+    return #states[proxy].target
+end
+
+local function proxy_next(table, key)
+    local state = states[table]
+    if not state then
+        return l_next(table, key)
+    end
+    local next_key, value = l_next(state.target, key)
+    if next_key ~= nil then
+        return next_key, wrap(value, state.root, append(state.path, next_key))
+    end
+end
+local function proxy_pairs(table)
+    if states[table] then
+        return proxy_next, table, nil
+    end
+    return l_pairs(table)
+end
+local function proxy_ipairs_iter(table, i)
+    i = i + 1
+    local value = table[i]
+    if value ~= nil then
+        return i, value
+    end
+end
+local function proxy_ipairs(table)
+    if states[table] then
+        return proxy_ipairs_iter, table, 0
+    end
+    return l_ipairs(table)
+end
+local function proxy_unpack(table, i, j)
+    local state = states[table]
+    if not state then
+        return l_unpack(table, i, j)
+    end
+    i, j = i or 1, j or #state.target
+    local values = {}
+    for k = i, j do
+        values[k - i + 1] = table[k]
+    end
+    return l_unpack(values, 1, j - i + 1)
+end
+
+local function proxy_insert(table, ...)
+    local state = states[table]
+    if not state then
+        return table_insert(table, ...)
+    end
+    local target = state.target
+    if select('#', ...) == 1 then
+        local value = ...
+        table_insert(target, unwrap(value, state.root))
+        record(state.root.changes, "insert", append(state.path, #target), 2)
+    else
+        local pos, value = ...
+        table_insert(target, pos, unwrap(value, state.root))
+        record(state.root.changes, "insert", append(state.path, pos), 2)
+    end
+end
+local function proxy_remove(table, pos)
+    local state = states[table]
+    if not state then
+        return table_remove(table, pos)
+    end
+    local target = state.target
+    local removed_pos = pos or #target
+    local value = table_remove(target, pos)
+    record(state.root.changes, "remove", append(state.path, removed_pos), 2)
+    return wrap(value, state.root, append(state.path, removed_pos))
+end
+local function proxy_sort(table, comp)
+    local state = states[table]
+    if not state then
+        return table_sort(table, comp)
+    end
+    local root, path = state.root, state.path
+    if comp then
+        table_sort(state.target, function(a, b)
+            return comp(wrap(a, root, path), wrap(b, root, path))
+        end)
+    else
+        table_sort(state.target)
+    end
+    record(root.changes, "sort", path, 2)
+end
+
+local function target_of(table)
+    local state = states[table]
+    return state and state.target or table
+end
+local function proxy_concat(table, ...)
+    return table_concat(target_of(table), ...)
+end
+local function proxy_len(table)
+    return #target_of(table)
+end
+local function proxy_isempty(table)
+    return table_isempty(target_of(table))
+end
+local function proxy_nkeys(table)
+    return table_nkeys(target_of(table))
+end
+local function proxy_type(value)
+    if states[value] then
+        return "table"
+    end
+    return type(value)
+end
+local function proxy_clone(table)
+    local state = states[table]
+    if not state then
+        return table_clone(table)
+    end
+    local clone = {}
+    for k, v in proxy_next, table do
+        clone[k] = v
+    end
+    return clone
+end
+
+-- Wraps the Lua mirror of a rule in a proxy that records changes into `changes`.
+local function wrap_mirror(mirror, changes)
+    return wrap(mirror, { changes = changes, proxies = setmetatable({}, { __mode = "k" }) }, {})
+end
+
+-- Records a change to a whole rule, made by the function `level` levels up the stack.
+local function record_change(changes, kind, level)
+    record(changes, kind, {}, (level or 1) + 1)
+end
+
+return {
+    wrap_mirror = wrap_mirror,
+    unwrap = unwrap,
+    record_change = record_change,
+
+    next = proxy_next,
+    pairs = proxy_pairs,
+    ipairs = proxy_ipairs,
+    unpack = proxy_unpack,
+    insert = proxy_insert,
+    remove = proxy_remove,
+    sort = proxy_sort,
+    concat = proxy_concat,
+    len = proxy_len,
+    isempty = proxy_isempty,
+    nkeys = proxy_nkeys,
+    clone = proxy_clone,
+    type = proxy_type,
+}
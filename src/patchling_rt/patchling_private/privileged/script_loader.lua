@@ -0,0 +1,168 @@
+-- NOTE: This is privileged code and has access to functions that should not be available to user code.
+--       Take extra care when editing this file.
+
+-- Runs the patch scripts of mods. Each script runs in an environment of its own, and `require` in a
+-- script only finds modules in the library directories of the mod the script belongs to, or modules
+-- that are part of Patchling.
+
+local checks = checks
+local error = error
+local getfenv = getfenv
+local globals = _G
+local loaded = package.loaded
+local loadstring = loadstring
+local pcall = pcall
+local require = require
+local setfenv = setfenv
+local setmetatable = setmetatable
+local string_match = string.match
+local tonumber = tonumber
+local tostring = tostring
+local type = type
+local xpcall = xpcall
+
+local loader = require "patchling_private.privileged.metalua_loader"
+local traceback_module = require "patchling_private.privileged.traceback"
+local locate = traceback_module.locate
+local resolve_location = traceback_module.resolve_location
+local register_file = traceback_module.register_file
+local traceback = traceback_module.traceback
+
+-- Creates a new environment for a script or module. Globals it sets do not leak into other scripts.
+-- The metatable is hidden, and `getfenv` returns the environment in place of the shared globals, so
+-- scripts cannot reach the globals to change them.
+local function new_env(require)
+    local env = setmetatable({}, { __index = globals, __metatable = false })
+    env._G = env
+    env.require = require
+    env.getfenv = function(f)
+        if f == nil then
+            f = 2
+        elseif type(f) == "number" and f > 0 then
+            f = f + 1
+        end
+        local fenv = getfenv(f)
+        if fenv == globals then
+            return env
+        end
+        return fenv
+    end
+    return env
+end
+
+-- Maps each mod to `{ require = function, env = table }`. Modules loaded from a mod's library
+-- directories are shared by all of its scripts, and run in an environment shared by the mod.
+local mod_states = {}
+
+local function mod_state(mod_name)
+    local state = mod_states[mod_name]
+    if not state then
+        local modules, loading = {}, {}
+        local function mod_require(name)
+            checks('string')
+
+            local value = modules[name]
+            if value ~= nil then
+                return value
+            end
+            if loading[name] then
+                error("loop or previous error loading module '"..name.."'", 2)
+            end
+
+            local fn = loader.find_module(name, mod_name)
+            if not fn then
+                -- the module is not part of the mod, so it must be part of Patchling or the
+                -- standard library.
+                return require(name)
+            end
+            loading[name] = true
+            setfenv(fn, state.env)
+            local result = fn(name)
+            loading[name] = nil
+            if result == nil then
+                result = true
+            end
+            modules[name] = result
+            return result
+        end
+
+        state = { require = mod_require, env = new_env(mod_require) }
+        mod_states[mod_name] = state
+    end
+    return state
+end
+
+local function script_error(mod_name, module_name, line, message, traceback)
+    return {
+        mod_name = mod_name,
+        module = module_name,
+        line = line,
+        message = message,
+        traceback = traceback,
+    }
+end
+
+-- Removes the position Lua adds to the start of an error message, if it is the line of the loaded
+-- chunk the error is reported at.
+local function strip_position(message, line)
+    local position, rest = string_match(message, "^[^\n]-:(%d+): (.*)$")
+    if position and tonumber(position) == line then
+        return rest
+    end
+    return message
+end
+
+local function compile(source, file_name, is_metalua)
+    if is_metalua then
+        local compiler = loaded["metalua.compiler"]
+        if not compiler then
+            return nil, "no metalua compiler"
+        end
+        local ok, fn, err = pcall(function()
+            return compiler.new():src_to_function(source, "@"..file_name)
+        end)
+        if not ok then
+            return nil, fn
+        end
+        return fn, err
+    else
+        return loadstring(source, "@"..file_name)
+    end
+end
+
+-- Runs a script from a mod. Returns nothing if the script succeeded, or a table describing the error
+-- it raised otherwise.
+local function execute_script(mod_name, file_name, module_name, source, is_metalua)
+    checks('string', 'string', 'string', 'string', 'boolean')
+
+    local fn, err = compile(source, file_name, is_metalua)
+    if not fn then
+        err = tostring(err)
+        local raw_line = tonumber(string_match(err, "^[^\n]-:(%d+): "))
+        local _, _, line = resolve_location(file_name, raw_line)
+        return script_error(mod_name, module_name, line, strip_position(err, raw_line), nil)
+    end
+    register_file(file_name, module_name, mod_name)
+    setfenv(fn, new_env(mod_state(mod_name).require))
+
+    local function handler(message)
+        local err_mod, err_module, line, raw_line = locate(2)
+        if not err_mod then
+            err_mod, err_module = mod_name, module_name
+        end
+        message = strip_position(tostring(message), raw_line)
+        return script_error(err_mod, err_module, line, message, traceback(nil, 2))
+    end
+    local ok, result = xpcall(fn, handler)
+    if not ok then
+        if type(result) ~= "table" then
+            -- the error handler itself failed.
+            return script_error(mod_name, module_name, nil, tostring(result), nil)
+        end
+        return result
+    end
+end
+
+return {
+    execute_script = execute_script,
+}
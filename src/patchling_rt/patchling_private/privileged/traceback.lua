@@ -11,10 +11,36 @@ local table_concat = table.concat
 local table_insert = table.insert
 local type = type
 
--- TODO: Finish traceback, implement lineinfo for ast_to_src
-
 local module_names = {}
 
+-- Maps files compiled from metalua to tables mapping lines of the generated source to lines of the
+-- metalua source.
+local line_maps = {}
+
+-- Returns the line of the original source that a line of a loaded file was generated from.
+local function map_line(file_name, line)
+    local line_map = file_name and line_maps[file_name]
+    if line_map and line then
+        return line_map[line] or line
+    end
+    return line
+end
+
+-- Resolves a line of a loaded file to the mod and module the file was registered as, and the line
+-- of the original source. Every lookup of a script location goes through this, so locations always
+-- refer to the source a mod author wrote. The mod and module are nil if the file is not registered.
+local function resolve_location(file_name, line)
+    if line == -1 then
+        line = nil
+    end
+    local names = file_name and module_names[file_name]
+    local mapped_line = map_line(file_name, line)
+    if names then
+        return names.mod_name, names.module_name, mapped_line
+    end
+    return nil, nil, mapped_line
+end
+
 local function traceback(thread, message, level)
     if type(thread) ~= "thread" then
         message, level = thread, message
@@ -37,13 +63,16 @@ local function traceback(thread, message, level)
 
         table_insert(accum, string.format("\n%4d: ", frame - 1))
 
+        local source
         if string_sub(info.source, 1, 1) == "@" then
-            local source = string_sub(info.source, 2)
-            if module_names[source] then
-                local names = module_names[source]
-                table_insert(accum, names.mod_name)
+            source = string_sub(info.source, 2)
+        end
+        local mod_name, module_name, line = resolve_location(source, info.currentline)
+        if source then
+            if mod_name then
+                table_insert(accum, mod_name)
                 table_insert(accum, ":")
-                table_insert(accum, names.module_name)
+                table_insert(accum, module_name)
             elseif string_gmatch(source, "lua_modules/share/5.1") then
                 table_insert(accum, "patchling:<bootstrap>/")
                 local short_source = string_gsub(source, ".*/", "")
@@ -54,9 +83,9 @@ local function traceback(thread, message, level)
         else
             table_insert(accum, info.short_src)
         end
-        if info.linedefined ~= -1 then
+        if line then
             table_insert(accum, ":")
-            table_insert(accum, info.linedefined) -- TODO: lineinfo
+            table_insert(accum, line)
         end
         table_insert(accum, ": ")
 
@@ -73,6 +102,32 @@ local function traceback(thread, message, level)
     return table_concat(accum)
 end
 
+-- Finds the innermost function on the stack that was loaded from a mod, starting from `level` with
+-- the same levels as `error`. Returns the mod and module it was loaded from, and the line it is
+-- currently running, followed by the line of the loaded chunk it is running. Patchling's own modules
+-- are skipped, so errors raised in them are reported where a mod called into them.
+local function locate(level)
+    local frame = (level or 1) + 1
+    while true do
+        local info = debug_getinfo(frame, "Sl")
+        if not info then
+            return nil
+        end
+        if string_sub(info.source, 1, 1) == "@" then
+            local source = string_sub(info.source, 2)
+            local mod_name, module_name, line = resolve_location(source, info.currentline)
+            if mod_name and mod_name ~= "patchling" then
+                local raw_line = info.currentline
+                if raw_line == -1 then
+                    raw_line = nil
+                end
+                return mod_name, module_name, line, raw_line
+            end
+        end
+        frame = frame + 1
+    end
+end
+
 local function register_file(file_name, module_name, mod_name)
     module_names[file_name] = {
         module_name = module_name,
@@ -80,7 +135,14 @@ local function register_file(file_name, module_name, mod_name)
     }
 end
 
+local function register_line_map(file_name, line_map)
+    line_maps[file_name] = line_map
+end
+
 return {
     traceback = traceback,
+    locate = locate,
+    resolve_location = resolve_location,
     register_file = register_file,
+    register_line_map = register_line_map,
 }
\ No newline at end of file